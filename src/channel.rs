@@ -1,20 +1,31 @@
 use {
     bytes::Bytes,
+    serde_json::json,
     std::net::{SocketAddr, SocketAddrV4},
+    std::os::fd::OwnedFd,
+    std::path::PathBuf,
+    std::sync::atomic::{AtomicU32, Ordering},
     std::sync::{Arc, Mutex},
+    tokio::fs::{File, OpenOptions},
+    tokio::io::AsyncWriteExt,
     tokio::process::{Child, Command as ProcessCommand},
-    tokio::sync::{broadcast, mpsc, oneshot},
+    tokio::sync::{Notify, broadcast, mpsc, oneshot},
+    tokio::time::{Instant, timeout_at},
     warp::ws::Message,
 };
 
 use crate::{
     cli::Config,
+    control::{self, Control},
     envvars::CGIEnv,
     error::{AppError, AppResult},
-    message::{Address, deserialize},
+    limits::{Cgroup, Limits, exceeded_limit},
+    message::{Address, deserialize, strip_fields},
     types::{
-        CacheBuffer, Caching, Event, EventTx, Framing, FromProcessTx, PortID, ProcessSenders,
-        RoomID, ShutdownRx, ShutdownTx, ToProcessRx, ToProcessTx,
+        BindRx, BindTx, CacheBuffer, Caching, ConnID, ControlCommand, ControlRx, ControlTx, Event,
+        EventTx, ExitReason, Frame, Framing, FromProcessTx, Header, Lag, LifecycleRx, LifecycleTx,
+        PortID, ProcessSenders, Recipients, Restart, RoomID, SharedGroups, ShutdownRx, ShutdownTx,
+        Stderr, StopSignal, ToProcessRx, ToProcessTx,
     },
     utils::run,
 };
@@ -23,24 +34,73 @@ use crate::{
 pub struct Channel {
     pub source: Option<Source>,
     pub room: RoomID,
+    pub conn: Option<ConnID>,
+    pub port: Option<PortID>,
     pub is_binary: bool,
     pub delimiters: String,
     pub attach_delay: Option<u64>,
+    pub ready_msg: Option<String>,
+    pub startup_timeout: u64,
+    pub stop_signal: StopSignal,
+    pub stop_timeout: u64,
+    pub exit_msg: Option<String>,
+    pub restart: Restart,
+    pub restart_max: u32,
+    pub restart_delay: u64,
+    pub stderr: Vec<Stderr>,
+    pub stderr_dir: Option<PathBuf>,
+    pub stderr_file: Option<File>,
+    pub limits: Limits,
+    pub cgroup: Option<Cgroup>,
     pub framing: Framing,
     pub caching: Caching,
+    pub lag: Lag,
+    pub strip: Vec<String>,
+    pub server_buffer: usize,
     pub tx: ToProcessTx,
     pub rx: Option<ToProcessRx>,
+    pub lifecycle_tx: LifecycleTx,
+    pub lifecycle_rx: Option<LifecycleRx>,
     pub cast_tx: FromProcessTx,
+    pub drained: Arc<Notify>,
+    pub exits: Arc<Mutex<u32>>,
     pub kill_rx: Option<ShutdownRx>,
     pub kill_tx: Option<ShutdownTx>,
     pub event_tx: Option<EventTx>,
+    pub bind_rx: Option<BindRx>,
+    pub bind_msg: Option<String>,
     pub cache: Option<Arc<Mutex<CacheBuffer>>>,
+    pub groups: SharedGroups,
+    pub is_control: bool,
+    pub control_fd: Option<OwnedFd>,
+    pub control_tx: ControlTx,
+    pub control_rx: Option<ControlRx>,
 }
 
 #[derive(Debug)]
 pub enum Source {
     Stdio(Command),
     Tcp(Command, SocketAddr),
+    Unix(Command, PathBuf),
+    WebSocket(Command, String),
+}
+
+impl Drop for Source {
+    fn drop(&mut self) {
+        if let Source::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+static SOCKET_NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+/// Unique path for the unix socket of a child
+fn socket_path(dir: Option<&PathBuf>) -> PathBuf {
+    let id = SOCKET_NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    dir.cloned()
+        .unwrap_or_else(std::env::temp_dir)
+        .join(format!("scalesocket-{}-{}.sock", std::process::id(), id))
 }
 
 impl Channel {
@@ -51,23 +111,39 @@ impl Channel {
         env: CGIEnv,
         cache: Option<Arc<Mutex<CacheBuffer>>>,
     ) -> Self {
-        let (tx, rx) = mpsc::unbounded_channel::<Message>();
-        let cast_tx = broadcast::Sender::new(16);
+        let (tx, rx) = mpsc::channel::<Message>(config.client_buffer.get());
+        let (lifecycle_tx, lifecycle_rx) = mpsc::unbounded_channel();
+        let cast_tx = broadcast::Sender::new(config.server_buffer.get());
         let (kill_tx, kill_rx) = oneshot::channel();
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
 
+        let limits = Limits::from(config);
         let cmd = run(
             &config.cmd,
             config.args.clone(),
             port,
             env.into(),
             &config.passenv,
+            !config.stderr.is_empty(),
         );
-        let source = match &config.tcp {
-            true => {
+        let mut cmd = Command::new(cmd);
+        cmd.limit(&limits);
+
+        let source = match (config.tcp, config.ws, config.unix) {
+            (true, _, _) => {
                 let addr = SocketAddrV4::new("127.0.0.1".parse().unwrap(), port.unwrap()).into();
-                Some(Source::Tcp(Command::new(cmd), addr))
+                Some(Source::Tcp(cmd, addr))
             }
-            false => Some(Source::Stdio(Command::new(cmd))),
+            (_, true, _) => {
+                let url = format!("ws://127.0.0.1:{}{}", port.unwrap(), config.ws_path);
+                Some(Source::WebSocket(cmd, url))
+            }
+            (_, _, true) => {
+                let path = socket_path(config.socket_dir.as_ref());
+                cmd.0.env("SOCKET_PATH", &path);
+                Some(Source::Unix(cmd, path))
+            }
+            _ => Some(Source::Stdio(cmd)),
         };
 
         let mut delimiters = config.delimiters.clone().unwrap_or_default();
@@ -79,63 +155,255 @@ impl Channel {
             source,
             is_binary: config.binary,
             room: room.to_string(),
+            conn: None,
+            port,
             attach_delay: config.delay,
+            ready_msg: config.ready_msg.clone(),
+            startup_timeout: config.startup_timeout,
+            stop_signal: config.stop_signal,
+            stop_timeout: config.stop_timeout,
+            exit_msg: config.exit_msg.clone(),
+            restart: config.restart,
+            restart_max: config.restart_max,
+            restart_delay: config.restart_delay,
+            stderr: config.stderr.clone(),
+            stderr_dir: config.stderr_dir.clone(),
+            stderr_file: None,
+            limits,
+            cgroup: None,
             delimiters,
             framing: config.into(),
             caching: config.into(),
+            lag: config.lag,
+            strip: config.strip.clone().unwrap_or_default(),
+            server_buffer: config.server_buffer.get(),
             tx,
             rx: Some(rx),
+            lifecycle_tx,
+            lifecycle_rx: Some(lifecycle_rx),
             cast_tx,
+            drained: Arc::new(Notify::new()),
+            exits: Arc::new(Mutex::new(0)),
             kill_tx: Some(kill_tx),
             kill_rx: Some(kill_rx),
             event_tx: None,
+            bind_rx: None,
+            bind_msg: None,
             cache,
+            groups: SharedGroups::default(),
+            is_control: config.control,
+            control_fd: None,
+            control_tx,
+            control_rx: Some(control_rx),
         }
     }
 
     pub fn take_senders(&mut self) -> ProcessSenders {
-        let proc_tx_broadcast = self.cast_tx.clone();
-        let proc_tx = self.tx.clone();
-        let kill_tx = self.kill_tx.take().unwrap();
-        (proc_tx_broadcast, proc_tx, kill_tx)
+        ProcessSenders {
+            cast_tx: self.cast_tx.clone(),
+            tx: self.tx.clone(),
+            lifecycle_tx: self.lifecycle_tx.clone(),
+            kill_tx: self.kill_tx.take().unwrap(),
+            control_tx: self.control_tx.clone(),
+            drained: self.drained.clone(),
+            exits: self.exits.clone(),
+        }
     }
 
     pub fn give_sender(&mut self, event_tx: EventTx) {
         self.event_tx = Some(event_tx);
     }
 
-    /// Send a message to the socket clients (or event bus)
-    pub fn write_sock(&mut self, msg: Bytes) {
-        let write_metadata = |event_tx: Option<&EventTx>, room: &str, value: serde_json::Value| {
-            let _ = event_tx
-                .expect("event_tx to be passed")
-                .send(Event::ProcessMeta {
-                    room: room.to_string(),
-                    value,
-                });
+    /// Leave the room unassigned until a binding is sent
+    pub fn take_bind_sender(&mut self) -> BindTx {
+        let (bind_tx, bind_rx) = oneshot::channel();
+        self.bind_rx = Some(bind_rx);
+        bind_tx
+    }
+
+    /// Place the process in its own cgroup, if enabled
+    ///
+    /// The cgroup is created once and reused when the process is restarted.
+    pub fn create_cgroup(&mut self) -> AppResult<()> {
+        if self.cgroup.is_some() {
+            return Ok(());
+        }
+        let Some(cgroup) = self.limits.cgroup() else {
+            return Ok(());
+        };
+        let cgroup = cgroup.map_err(|e| AppError::ProcessSpawnError(format!("cgroup: {e}")))?;
+
+        match self.source.as_mut() {
+            Some(
+                Source::Stdio(cmd)
+                | Source::Tcp(cmd, _)
+                | Source::Unix(cmd, _)
+                | Source::WebSocket(cmd, _),
+            ) => cmd.attach(&cgroup)?,
+            None => {}
+        }
+        self.cgroup = Some(cgroup);
+        Ok(())
+    }
+
+    /// Open the control channel to the process, if enabled
+    ///
+    /// The channel is opened once and passed to the process again when restarted.
+    pub fn open_control(&mut self) -> AppResult<Option<Control>> {
+        if !self.is_control || self.control_fd.is_some() {
+            return Ok(None);
+        }
+        let Some(
+            Source::Stdio(cmd)
+            | Source::Tcp(cmd, _)
+            | Source::Unix(cmd, _)
+            | Source::WebSocket(cmd, _),
+        ) = self.source.as_mut()
+        else {
+            return Ok(None);
+        };
+        let (control, fd) = cmd.control()?;
+        self.control_fd = Some(fd);
+        Ok(Some(control))
+    }
+
+    /// Check if the process output can be read, according to the lag policy
+    ///
+    /// With `Lag::Block`, output is not read while any client is a full buffer behind.
+    pub fn can_write_sock(&self) -> bool {
+        self.lag != Lag::Block || self.cast_tx.len() < self.server_buffer
+    }
+
+    /// Wait until lagging clients catch up, or the deadline passes
+    pub async fn wait_for_clients(&self, deadline: Instant) {
+        while !self.can_write_sock() {
+            if timeout_at(deadline, self.drained.notified()).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Check if messages to the process are written as is, without a trailing newline
+    pub fn is_binary_in(&self) -> bool {
+        self.is_binary
+            || matches!(
+                self.framing.socket_to_process(),
+                Some(
+                    Frame::GWSocket
+                        | Frame::LenPrefix
+                        | Frame::Length
+                        | Frame::MsgPack
+                        | Frame::CBOR
+                )
+            )
+    }
+
+    /// Check if messages from the process are sent to clients as binary
+    pub fn is_binary_out(&self) -> bool {
+        self.is_binary
+            || matches!(
+                self.framing.process_to_socket(),
+                Some(Frame::LenPrefix | Frame::Length | Frame::MsgPack | Frame::CBOR)
+            )
+    }
+
+    /// Run a command written by the process to its control channel
+    pub async fn write_control(&mut self, line: String) {
+        let cmd = match serde_json::from_str::<ControlCommand>(&line) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                tracing::warn!(room = self.room, "invalid control command: {}", e);
+                return;
+            }
         };
-        let write_cache = |cache: Option<&Arc<Mutex<CacheBuffer>>>, msg: Message| {
+
+        match cmd {
+            ControlCommand::Meta { data } => self.set_metadata(data).await,
+            ControlCommand::Kick { conn, code, reason } => self.kick(Some(conn), code, reason),
+            ControlCommand::GroupAdd { group, conn } => {
+                self.update_groups(conn, Some(&group), None)
+            }
+            ControlCommand::GroupRemove { group, conn } => {
+                self.update_groups(conn, None, Some(&group))
+            }
+            ControlCommand::Log { msg } => tracing::info!(room = self.room, "{}", msg),
+        }
+    }
+
+    /// Send a message to the socket clients (or event bus)
+    pub async fn write_sock(&mut self, msg: Bytes) {
+        self.write_sock_as(msg, self.is_binary_out()).await;
+    }
+
+    /// Send a websocket frame to the socket clients (or event bus), preserving its type
+    pub async fn write_sock_frame(&mut self, msg: Message) {
+        let is_binary = msg.is_binary();
+        self.write_sock_as(Bytes::from(msg.into_bytes()), is_binary)
+            .await;
+    }
+
+    async fn write_sock_as(&mut self, msg: Bytes, is_binary: bool) {
+        let write_cache = |cache: Option<&Arc<Mutex<CacheBuffer>>>, msg: (Header, Message)| {
             if let Some(cache) = cache {
                 cache.lock().expect("poisoned lock").write(msg);
             }
         };
 
-        match deserialize(&msg, self.framing.process_to_socket()) {
-            Ok((h, _)) if h.is_meta && self.is_binary => {
+        let frame = self.framing.process_to_socket();
+        match deserialize(&msg, frame) {
+            Ok((h, _)) if h.is_group_control() => {
+                let Some(conn) = h.conn else {
+                    tracing::warn!(room = self.room, "group message without _conn");
+                    return;
+                };
+                self.update_groups(conn, h.group_add.as_deref(), h.group_remove.as_deref());
+            }
+            Ok((h, _)) if h.kick.is_some() => {
+                self.kick(h.kick, h.code, h.reason);
+            }
+            Ok((h, msg)) if h.is_meta && matches!(frame, Some(Frame::MsgPack)) => {
+                let value = rmp_serde::from_slice(&msg).unwrap_or_default();
+                self.set_metadata(value).await;
+            }
+            Ok((h, msg)) if h.is_meta && matches!(frame, Some(Frame::CBOR)) => {
+                let value = ciborium::de::from_reader(&msg[..]).unwrap_or_default();
+                self.set_metadata(value).await;
+            }
+            Ok((h, _)) if h.is_meta && is_binary => {
                 tracing::warn!("binary metadata is not supported");
             }
             Ok((h, msg)) if h.is_meta => {
-                let value = serde_json::from_slice(msg).unwrap_or_default();
-                write_metadata(self.event_tx.as_ref(), &self.room, value);
+                let value = serde_json::from_slice(&msg).unwrap_or_default();
+                self.set_metadata(value).await;
             }
-            Ok((h, msg)) => {
-                let msg = match self.is_binary {
+            Ok((mut h, mut msg)) => {
+                if !self.strip.is_empty() {
+                    msg = strip_fields(msg, frame, &self.strip);
+                }
+
+                // Resolve group to its current members
+                if let Some(group) = h.to_group.take() {
+                    let mut members = self.groups.read().expect("poisoned lock").members(&group);
+                    match h.to.take() {
+                        Some(Recipients::One(to)) => members.push(to),
+                        Some(Recipients::Many(to)) => members.extend(to),
+                        None => {}
+                    }
+                    h.to = Some(Recipients::Many(members));
+                }
+
+                // Envelope carries the type of its data
+                let is_binary = match frame {
+                    Some(Frame::Envelope) => h.is_binary,
+                    _ => is_binary,
+                };
+                let msg = match is_binary {
                     true => Message::binary(msg),
-                    false => Message::text(std::str::from_utf8(msg).unwrap_or_default()),
+                    false => Message::text(std::str::from_utf8(&msg).unwrap_or_default()),
                 };
 
                 if self.caching.matches(&h) {
-                    write_cache(self.cache.as_ref(), msg.clone());
+                    write_cache(self.cache.as_ref(), msg.clone().header(h.clone()));
                 }
 
                 let _ = self.cast_tx.send(msg.header(h));
@@ -145,6 +413,194 @@ impl Channel {
             }
         }
     }
+
+    async fn set_metadata(&self, value: serde_json::Value) {
+        let _ = self
+            .event_tx
+            .as_ref()
+            .expect("event_tx to be passed")
+            .send(Event::ProcessMeta {
+                room: self.room.to_string(),
+                value,
+            })
+            .await;
+    }
+
+    fn update_groups(&self, conn: ConnID, add: Option<&str>, remove: Option<&str>) {
+        let mut groups = self.groups.write().expect("poisoned lock");
+        if let Some(group) = add {
+            groups.add(group, conn);
+        }
+        if let Some(group) = remove {
+            groups.remove(group, conn);
+        }
+    }
+
+    /// Close the connections of the clients, with a close code and reason
+    fn kick(&self, to: Option<Recipients>, code: Option<u16>, reason: Option<String>) {
+        let code = match code {
+            Some(code) if is_valid_close_code(code) => code,
+            Some(code) => {
+                tracing::warn!(room = self.room, "invalid close code {}", code);
+                CLOSE_POLICY
+            }
+            None => CLOSE_POLICY,
+        };
+        let reason = reason.unwrap_or_else(|| "kicked by process".to_string());
+        tracing::info!(room = self.room, to = ?to, "process kicked client");
+
+        let header = Header {
+            to,
+            ..Default::default()
+        };
+        let _ = self
+            .cast_tx
+            .send(Message::close_with(code, reason).header(header));
+    }
+
+    /// Send a line from the process stderr to its destinations
+    pub async fn write_stderr(&mut self, line: String) {
+        if self.stderr.contains(&Stderr::Log) {
+            tracing::info!(room = self.room, "stderr: {}", line);
+        }
+
+        if self.stderr.contains(&Stderr::Clients) {
+            let msg = json!({"t": "Stderr", "data": line}).to_string();
+            let _ = self
+                .cast_tx
+                .send(Message::text(msg).header(Header::broadcast()));
+        }
+
+        // Pooled processes have no room, and thus no file, until bound
+        if self.stderr.contains(&Stderr::File) && !self.room.is_empty() {
+            if self.stderr_file.is_none() {
+                let dir = self.stderr_dir.clone().unwrap_or_default();
+                let path = dir.join(format!("{}.log", urlencoding::encode(&self.room)));
+                match OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .await
+                {
+                    Ok(file) => self.stderr_file = Some(file),
+                    Err(e) => tracing::warn!("failed to open {}: {}", path.display(), e),
+                }
+            }
+            if let Some(ref mut file) = self.stderr_file {
+                let line = format!("{line}\n");
+                if let Err(e) = file.write_all(line.as_bytes()).await {
+                    tracing::warn!("failed to write stderr: {}", e);
+                }
+                let _ = file.flush().await;
+            }
+        }
+    }
+
+    /// Check if the process should be restarted after it ended
+    ///
+    /// Processes are only restarted while clients are connected.
+    pub fn should_restart(&self, (code, reason): (Option<i32>, ExitReason), restarts: u32) -> bool {
+        let is_restartable = match self.restart {
+            Restart::Never => false,
+            Restart::OnFailure => code != Some(0),
+            Restart::Always => true,
+        };
+
+        is_restartable
+            && matches!(reason, ExitReason::Exited | ExitReason::Limit(_))
+            && restarts < self.restart_max
+            && self.cast_tx.receiver_count() > 0
+    }
+
+    /// Determine why the process exited on its own, given its terminating signal
+    pub fn exit_reason(&mut self, signal: Option<i32>) -> ExitReason {
+        match exceeded_limit(signal, self.cgroup.as_mut()) {
+            Some(limit) => ExitReason::Limit(limit),
+            None => ExitReason::Exited,
+        }
+    }
+
+    /// Inform the event bus that the process has ended
+    pub async fn notify_exit(&self, (code, reason): (Option<i32>, ExitReason)) {
+        if let Some(ref event_tx) = self.event_tx {
+            // if sending fails, the events::handle has already been torn down
+            let _ = event_tx
+                .send(Event::ProcessExit {
+                    room: self.room.clone(),
+                    conn: self.conn,
+                    code,
+                    reason,
+                    port: self.port,
+                })
+                .await;
+        }
+    }
+
+    /// Inform the event bus that the process is being restarted
+    pub async fn notify_restart(&self, (code, reason): (Option<i32>, ExitReason), attempt: u32) {
+        if let Some(ref event_tx) = self.event_tx {
+            let _ = event_tx
+                .send(Event::ProcessRestart {
+                    room: self.room.clone(),
+                    conn: self.conn,
+                    code,
+                    reason,
+                    attempt,
+                })
+                .await;
+        }
+    }
+
+    /// Inform the socket clients that the process has ended, and close their connections
+    ///
+    /// An `exit` of `None` means that the process failed to start or was lost.
+    /// Lagging clients are waited for until the deadline, see `Lag::Block`.
+    pub async fn close_sock(&mut self, exit: Option<(Option<i32>, ExitReason)>, deadline: Instant) {
+        if let Some(ref exit_msg_template) = self.exit_msg {
+            self.wait_for_clients(deadline).await;
+            let code = exit.and_then(|(code, _)| code);
+            let code = code.map_or("null".to_string(), |c| c.to_string());
+            let exit_msg = exit_msg_template.replace("#CODE", &code);
+            let _ = self
+                .cast_tx
+                .send(Message::text(exit_msg).header(Header::broadcast()));
+        }
+
+        let close_msg = match exit {
+            Some((Some(0), ExitReason::Exited)) => {
+                Message::close_with(CLOSE_NORMAL, "process exited")
+            }
+            Some((Some(code), ExitReason::Exited)) => {
+                Message::close_with(CLOSE_ERROR, format!("process exited with code {code}"))
+            }
+            Some((None, ExitReason::Exited)) => {
+                Message::close_with(CLOSE_ERROR, "process terminated by signal")
+            }
+            Some((_, ExitReason::Stopped | ExitReason::Killed)) => {
+                Message::close_with(CLOSE_GOING_AWAY, "process stopped")
+            }
+            Some((_, ExitReason::Limit(limit))) => {
+                Message::close_with(CLOSE_ERROR, format!("process exceeded {limit} limit"))
+            }
+            None => Message::close_with(CLOSE_ERROR, "process failed"),
+        };
+        self.wait_for_clients(deadline).await;
+        let _ = self.cast_tx.send(close_msg.header(Header::broadcast()));
+    }
+}
+
+/// Websocket close code for a process that exited successfully
+const CLOSE_NORMAL: u16 = 1000;
+/// Websocket close code for a process that was stopped by scalesocket
+const CLOSE_GOING_AWAY: u16 = 1001;
+/// Websocket close code for a process that crashed or failed
+const CLOSE_ERROR: u16 = 1011;
+/// Websocket close code for a client kicked by the process, or disconnected for lagging behind
+pub const CLOSE_POLICY: u16 = 1008;
+
+/// Check if a close code may be sent to clients
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999)
 }
 
 #[derive(Debug)]
@@ -155,7 +611,24 @@ impl Command {
         Self(cmd)
     }
 
-    pub fn spawn(mut self) -> AppResult<Child> {
+    /// Apply resource limits to the child
+    pub fn limit(&mut self, limits: &Limits) {
+        limits.apply(&mut self.0);
+    }
+
+    /// Move the child into the cgroup when spawned
+    pub fn attach(&mut self, cgroup: &Cgroup) -> AppResult<()> {
+        cgroup
+            .attach(&mut self.0)
+            .map_err(|e| AppError::ProcessSpawnError(format!("cgroup: {e}")))
+    }
+
+    /// Pass a control channel to the child when spawned
+    pub fn control(&mut self) -> AppResult<(Control, OwnedFd)> {
+        control::open(&mut self.0).map_err(|e| AppError::ProcessSpawnError(format!("control: {e}")))
+    }
+
+    pub fn spawn(&mut self) -> AppResult<Child> {
         self.0
             .spawn()
             .map_err(|e| AppError::ProcessSpawnError(e.to_string()))
@@ -2,11 +2,12 @@ use {
     clap::builder::ArgPredicate,
     clap::{ArgAction, Parser},
     std::net::SocketAddr,
+    std::num::NonZeroUsize,
     std::ops::Range,
     std::path::PathBuf,
 };
 
-use crate::types::{Cache, Frame, Log};
+use crate::types::{Cache, Frame, Lag, Log, Restart, Stderr, StopSignal};
 
 const CACHE_SIZES: &[usize; 3] = &[1, 8, 64];
 
@@ -34,14 +35,56 @@ pub struct Config {
     /// Preserve server message history for room even after last client disconnects
     pub cache_persist: bool,
 
-    /// Delay before attaching to child
+    /// Emit message to pooled child when it is assigned to a room (use #ROOM for room)
+    ///
+    /// See --pool for pooling processes.
+    #[clap(long = "bindmsg", value_name = "MSG", requires = "pool")]
+    pub bind_msg: Option<String>,
+
+    /// Capacity of the queue of client messages to the child
+    ///
+    /// When the queue is full, reading from clients is paused until the child catches up.
+    #[clap(long = "clientbuffer", value_name = "NUM", default_value = "64")]
+    pub client_buffer: NonZeroUsize,
+
+    /// Place each child in its own cgroup v2 under the given parent cgroup
     ///
-    /// [default: 1 with --tcp]
+    /// The parent must be writable by scalesocket and have the `memory` and `cpu` controllers enabled for its children.
+    /// See --cgroupmem and --cgroupcpu for setting caps.
+    #[clap(long, value_parser, value_name = "DIR")]
+    pub cgroup: Option<PathBuf>,
+
+    /// CPU cap for each child cgroup, in percent of a single CPU
     #[clap(
-        long = "delay",
-        value_name = "SECONDS",
-        default_value_if("tcp",  ArgPredicate::Equals("true".into()), Some("1"))
+        long = "cgroupcpu",
+        value_name = "PERCENT",
+        value_parser = clap::value_parser!(u64).range(1..=u32::MAX as u64),
+        requires = "cgroup"
+    )]
+    pub cgroup_cpu: Option<u64>,
+
+    /// Memory cap for each child cgroup, in megabytes
+    ///
+    /// A child exceeding the cap is killed by the kernel, and its exit is reported as exceeding the limit.
+    #[clap(
+        long = "cgroupmem",
+        value_name = "MB",
+        value_parser = clap::value_parser!(u64).range(1..=u32::MAX as u64),
+        requires = "cgroup"
     )]
+    pub cgroup_memory: Option<u64>,
+
+    /// Open a control channel to child on file descriptor 3. Use CONTROL_FD to find it
+    ///
+    /// The child receives JSON lines of `Room`, `Join` and `Leave` events, with the environment of each client.
+    /// The child can write JSON lines of `Meta`, `Kick`, `GroupAdd`, `GroupRemove` and `Log` commands, without framing its output.
+    #[clap(long, action)]
+    pub control: bool,
+
+    /// Delay before attaching to child
+    ///
+    /// With --tcp, --unix or --ws, the connection is retried until the child accepts it, so a delay is usually not needed.
+    #[clap(long = "delay", value_name = "SECONDS")]
     pub delay: Option<u64>,
 
     /// Process output items are terminated by given characters
@@ -58,6 +101,12 @@ pub struct Config {
     )]
     pub delimiters: Option<String>,
 
+    /// Capacity of the queue of connection and process events to the server
+    ///
+    /// When the queue is full, new connections and process events wait until the server catches up.
+    #[clap(long = "eventbuffer", value_name = "NUM", default_value = "1024")]
+    pub event_buffer: NonZeroUsize,
+
     /// Emit message to child on client connect (use #ID for id)
     #[clap(
         long = "joinmsg",
@@ -66,6 +115,12 @@ pub struct Config {
     )]
     pub join_msg: Option<String>,
 
+    /// Emit message to clients on process exit (use #CODE for exit code)
+    ///
+    /// The message is sent before the connections are closed. The exit code is `null` if the process was terminated by a signal.
+    #[clap(long = "exitmsg", value_name = "MSG")]
+    pub exit_msg: Option<String>,
+
     /// Enable JSON framing with default join and leave messages
     ///
     /// This option is equivalent to
@@ -79,6 +134,23 @@ pub struct Config {
     )]
     pub json: bool,
 
+    /// Policy for clients that fall more than --serverbuffer messages behind the child
+    ///
+    /// When set to `drop`, the oldest messages are dropped for the client.
+    /// When set to `disconnect`, the client is disconnected.
+    /// When set to `block`, the child output is not read until all clients catch up.
+    ///
+    /// [default: drop, possible values: drop, disconnect, block]
+    #[clap(
+        long,
+        value_parser,
+        value_name = "POLICY",
+        default_value = "drop",
+        hide_possible_values = true,
+        hide_default_value = true
+    )]
+    pub lag: Lag,
+
     /// Emit message to child on client disconnect (use #ID for id)
     #[clap(
         long = "leavemsg",
@@ -87,6 +159,47 @@ pub struct Config {
     )]
     pub leave_msg: Option<String>,
 
+    /// Maximum CPU time of each child
+    ///
+    /// A child exceeding the limit is terminated with SIGXCPU, and its exit is reported as exceeding the limit.
+    #[clap(
+        long = "limitcpu",
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..=u32::MAX as u64)
+    )]
+    pub limit_cpu: Option<u64>,
+
+    /// Maximum number of open files of each child
+    ///
+    /// Opening more files fails in the child. How the child handles the failure is up to it,
+    /// so its exit is not reported as exceeding the limit.
+    #[clap(long = "limitfiles", value_name = "NUM")]
+    pub limit_files: Option<u64>,
+
+    /// Maximum address space of each child, in megabytes
+    ///
+    /// Allocating more memory fails in the child. How the child handles the failure is up to it,
+    /// so its exit is not reported as exceeding the limit. See --cgroupmem for a reported cap.
+    #[clap(
+        long = "limitmem",
+        value_name = "MB",
+        value_parser = clap::value_parser!(u64).range(1..=u32::MAX as u64)
+    )]
+    pub limit_memory: Option<u64>,
+
+    /// Maximum number of processes for the user running the children
+    ///
+    /// The limit applies to all processes of the user, not just to descendants of the child.
+    /// Spawning more processes fails in the child, so its exit is not reported as exceeding the limit.
+    #[clap(long = "limitprocs", value_name = "NUM")]
+    pub limit_procs: Option<u64>,
+
+    /// Keep room process alive after the last client disconnects
+    ///
+    /// During the idle period, clients reconnecting to the room are attached to the existing process.
+    #[clap(long, value_name = "SECONDS")]
+    pub linger: Option<u64>,
+
     /// Log format
     ///
     /// [default: text, possible values: text, json]
@@ -122,6 +235,33 @@ pub struct Config {
     )]
     pub passenv: Vec<String>,
 
+    /// Spawn a dedicated child for each connection, instead of one per room
+    ///
+    /// The child receives the join and leave messages of its own connection only.
+    /// See --maxrooms for limiting the number of processes.
+    #[clap(
+        long = "perconn",
+        action,
+        conflicts_with = "cache",
+        conflicts_with = "linger",
+        conflicts_with = "pool"
+    )]
+    pub per_conn: bool,
+
+    /// Number of pre-spawned processes waiting for a room
+    ///
+    /// When a client connects to a new room, a pooled process is assigned to the room instead of spawning a new one.
+    /// Pooled processes do not have the ROOM envvar set; use --bindmsg to inform them of the room.
+    /// The pool is refilled in the background, and pooled processes that exit are replaced after --restartdelay.
+    #[clap(long, value_name = "NUM", conflicts_with = "oneshot")]
+    pub pool: Option<usize>,
+
+    /// Wait for child to print given line on stdout before connecting to it
+    ///
+    /// Other output of the child on stdout is ignored.
+    #[clap(long = "readymsg", value_name = "MSG", requires = "socket")]
+    pub ready_msg: Option<String>,
+
     /// List of valid rooms
     ///
     /// When set, websocket connections are only accepted on the specified paths `/<ROOM>`.
@@ -132,6 +272,7 @@ pub struct Config {
     ///
     /// When set, websocket connections are accepted on up to <NUM> rooms.
     /// Since a child process is spawned for each room, this is equivalent to limiting the maximum number of processes.
+    /// With --perconn, the number of processes is limited instead of rooms.
     #[clap(
         long = "maxrooms",
         alias = "maxforks",
@@ -147,16 +288,43 @@ pub struct Config {
     ///
     /// When set to `json`, messages are parsed as JSON.
     /// Client messages are amended with an "_from" field.
-    /// Server messages are routed to clients based an optional "_to" field.
+    /// Server messages are routed to clients based an optional "_to" field, containing a client ID or a list of client IDs.
+    /// Clients listed in an optional "_except" field are skipped.
     ///
     /// Server messages with `_meta: true` will be dropped, and stored as room metadata accessible via the API.
     ///
+    /// Server messages with a "_group_add" or "_group_remove" field and a "_conn" field will be dropped, and add or remove the client to or from the named group.
+    /// Server messages with a "_to_group" field are routed to the members of the group. Groups are accessible via the API.
+    ///
+    /// Server messages with a "_kick" field will be dropped, and close the connection of the given client IDs.
+    /// The close code and reason are set by optional "_code" and "_reason" fields.
+    ///
     /// When set to `gwsocket`, messages are parsed according to gwsocket's strict mode.
+    /// Client messages are prefixed with a 12 byte header of the client ID, message type and length.
     /// Unparseable messages may be dropped.
     ///
+    /// When set to `lenprefix`, messages are prefixed with the payload length (u32) and client ID (u32) in big-endian byte order.
+    /// Client messages carry the sender ID. Server messages are routed to the given ID, or broadcast if it is 0.
+    /// Server messages are sent to clients as binary.
+    ///
+    /// When set to `length`, messages are prefixed only with the payload length (u32) in big-endian byte order, like `lenprefix` without the client ID.
+    /// Server messages are broadcast, and sent to clients as binary.
+    ///
+    /// When set to `msgpack`, messages are parsed as MessagePack maps, and routed like with `json`.
+    /// Client messages are amended with an "_from" field. Server messages are sent to clients as binary.
+    ///
+    /// When set to `cbor`, messages are parsed as CBOR maps, and handled like with `msgpack`.
+    ///
+    /// When set to `envelope`, client messages of any content are wrapped as {"_from": ID, "data": DATA}.
+    /// Binary data is base64 encoded, and marked with "_binary": true. Server messages in the same form are routed like with `json`,
+    /// and their data is unwrapped before being sent to clients.
+    ///
+    /// When set to `jsonrpc`, client messages are parsed as JSON-RPC 2.0 objects, and request IDs are namespaced with the client ID.
+    /// Server responses are sent only to the client that made the request, with the original ID. Notifications are broadcast.
+    ///
     /// See --serverframe and --clientframe for specifying framing independently.
     ///
-    /// [default: json with --json, possible values: gwsocket, json]
+    /// [default: json with --json, possible values: cbor, envelope, gwsocket, json, jsonrpc, length, lenprefix, msgpack]
     #[clap(
         long,
         value_parser,
@@ -182,6 +350,12 @@ pub struct Config {
     )]
     pub client_frame: Option<Frame>,
 
+    /// Capacity of the queue of child messages to each client
+    ///
+    /// See --lag for handling clients that fall behind.
+    #[clap(long = "serverbuffer", value_name = "NUM", default_value = "16")]
+    pub server_buffer: NonZeroUsize,
+
     /// Enable framing and routing for server originated messages
     ///
     /// See --frame for options.
@@ -195,10 +369,108 @@ pub struct Config {
     )]
     pub server_frame: Option<Frame>,
 
+    /// Capture child stderr line by line and route it to given destinations
+    ///
+    /// When set to `log`, lines are logged tagged with the room.
+    /// When set to `clients`, lines are sent to clients as `{"t":"Stderr","data":"<LINE>"}`.
+    /// When set to `file`, lines are appended to `<ROOM>.log` in the directory set by --stderrdir.
+    ///
+    /// [possible values: log, clients, file]
+    #[clap(
+        long,
+        value_parser,
+        value_name = "LIST",
+        value_delimiter = ',',
+        hide_possible_values = true
+    )]
+    pub stderr: Vec<Stderr>,
+
+    /// Remove reserved fields from server messages before they are sent to clients or cached
+    ///
+    /// Applies to server messages framed with `json`, `jsonrpc`, `msgpack` or `cbor`.
+    /// When set without a list, all fields used by scalesocket for routing and control are removed.
+    /// The `_from` field is kept, since it is not used for routing and identifies the sender to clients.
+    ///
+    /// [default: _to,_except,_to_group,_group_add,_group_remove,_conn,_kick,_code,_reason,_meta,_cache,_binary]
+    #[clap(
+        long,
+        value_name = "LIST",
+        value_delimiter = ',',
+        default_missing_value = "_to,_except,_to_group,_group_add,_group_remove,_conn,_kick,_code,_reason,_meta,_cache,_binary",
+        num_args = 0..,
+        require_equals = true,
+        hide_default_value = true
+    )]
+    pub strip: Option<Vec<String>>,
+
+    /// Directory for per-room stderr log files
+    #[clap(
+        long = "stderrdir",
+        value_parser,
+        value_name = "DIR",
+        required_if_eq("stderr", "file")
+    )]
+    pub stderr_dir: Option<PathBuf>,
+
     /// Serve static files from directory over HTTP
     #[clap(long = "staticdir", value_parser, value_name = "DIR")]
     pub static_dir: Option<PathBuf>,
 
+    /// Restart policy for child when it exits while clients are connected
+    ///
+    /// When set to `on-failure`, the child is restarted if it exits with a non-zero code or is terminated by a signal.
+    /// When set to `always`, the child is restarted regardless of how it exited.
+    /// On restart, the join message is emitted to the new child for each connected client.
+    ///
+    /// [default: never, possible values: never, on-failure, always]
+    #[clap(
+        long,
+        value_parser,
+        value_name = "POLICY",
+        default_value = "never",
+        hide_possible_values = true,
+        hide_default_value = true
+    )]
+    pub restart: Restart,
+
+    /// Initial delay before restarting child, doubled on each restart
+    #[clap(long = "restartdelay", value_name = "SECONDS", default_value = "1")]
+    pub restart_delay: u64,
+
+    /// Maximum number of restarts for a room
+    #[clap(long = "restartmax", value_name = "NUM", default_value = "3")]
+    pub restart_max: u32,
+
+    /// Signal sent to child when stopping it
+    ///
+    /// The child is stopped when the last client disconnects or scalesocket shuts down.
+    ///
+    /// [default: term, possible values: term, int, hup, quit, usr1, usr2, kill]
+    #[clap(
+        long = "stopsignal",
+        value_parser,
+        value_name = "SIGNAL",
+        default_value = "term",
+        hide_possible_values = true,
+        hide_default_value = true
+    )]
+    pub stop_signal: StopSignal,
+
+    /// Time to wait for child to exit after the stop signal, before sending SIGKILL
+    #[clap(long = "stoptimeout", value_name = "SECONDS", default_value = "5")]
+    pub stop_timeout: u64,
+
+    /// Time to wait for child to accept a TCP, unix socket or websocket connection, before giving up
+    ///
+    /// The connection is retried with backoff until the child is ready.
+    #[clap(
+        long = "startuptimeout",
+        value_name = "SECONDS",
+        default_value = "10",
+        requires = "socket"
+    )]
+    pub startup_timeout: u64,
+
     /// Expose room metadata API under /api/
     ///
     /// The exposed endpoints are:
@@ -209,21 +481,52 @@ pub struct Config {
     pub api: bool,
 
     /// Connect to child using TCP instead of stdio. Use PORT to bind
-    #[clap(long, action)]
+    #[clap(long, action, group = "socket", group = "port")]
     pub tcp: bool,
 
-    /// Port range for TCP
+    /// Port range for TCP and websocket
     ///
-    /// [default: 9001:9999 with --tcp]
+    /// [default: 9001:9999 with --tcp or --ws]
     #[clap(
         long = "tcpports",
         value_parser = parse_ports,
         value_name = "START:END",
-        requires = "tcp",
-        default_value_if("tcp",  ArgPredicate::Equals("true".into()), Some("9001:9999"))
+        requires = "port",
+        default_value_if("tcp",  ArgPredicate::Equals("true".into()), Some("9001:9999")),
+        default_value_if("ws",  ArgPredicate::Equals("true".into()), Some("9001:9999"))
     )]
     pub tcp_ports: Option<Range<u16>>,
 
+    /// Connect to child using a unix socket instead of stdio. Use SOCKET_PATH to bind
+    #[clap(long, action, group = "socket")]
+    pub unix: bool,
+
+    /// Directory for unix sockets
+    ///
+    /// [default: system temporary directory]
+    #[clap(
+        long = "socketdir",
+        value_parser,
+        value_name = "DIR",
+        requires = "unix"
+    )]
+    pub socket_dir: Option<PathBuf>,
+
+    /// Connect to child using websocket instead of stdio. Use PORT to bind
+    ///
+    /// Messages are forwarded frame by frame, preserving their text or binary type.
+    #[clap(long, action, group = "socket", group = "port")]
+    pub ws: bool,
+
+    /// Path of the websocket endpoint of the child
+    #[clap(
+        long = "wspath",
+        value_name = "PATH",
+        default_value = "/",
+        requires = "ws"
+    )]
+    pub ws_path: String,
+
     /// Increase level of verbosity
     #[clap(short, action = ArgAction::Count)]
     pub verbosity: u8,
@@ -1,32 +1,42 @@
 use {
     futures::stream,
-    futures::{FutureExt, StreamExt, TryFutureExt, TryStreamExt, future::ready},
-    sender_sink::wrappers::UnboundedSenderSink,
+    futures::{FutureExt, SinkExt, StreamExt, TryFutureExt, TryStreamExt, future::ready},
+    sender_sink::wrappers::SinkError,
     std::sync::Arc,
-    tokio::sync::Barrier,
+    tokio::sync::{Barrier, Notify},
+    tokio::time::{Duration, sleep},
     tokio::try_join,
     tokio_stream::wrappers::BroadcastStream,
     tokio_stream::wrappers::errors::BroadcastStreamRecvError,
+    tokio_util::sync::PollSender,
     tracing::instrument,
     warp::filters::ws::Message,
     warp::ws::WebSocket,
 };
 
 use crate::{
+    channel::CLOSE_POLICY,
     error::{AppError, AppResult},
     message::serialize,
-    types::{ConnID, Framing, FromProcessRx, Header, ToProcessTx},
+    types::{ConnID, Framing, FromProcessRx, Header, Lag, ToProcessTx},
 };
 
+/// Time to wait for the close message after the process has exited
+const PROCESS_CLOSE_GRACE: Duration = Duration::from_secs(1);
+
+#[allow(clippy::too_many_arguments)]
 #[instrument(parent = None, name = "connection", skip_all)]
 pub async fn handle(
     ws: WebSocket,
     conn: ConnID,
     framing: Framing,
+    lag: Lag,
     proc_rx: FromProcessRx,
     proc_tx: ToProcessTx,
+    drained: Arc<Notify>,
     barrier: Option<Arc<Barrier>>,
     cache: Vec<(Header, Message)>,
+    on_lag: impl Fn(u64) + Send,
 ) -> AppResult<()> {
     let proc_rx = BroadcastStream::new(proc_rx);
     let (sock_tx, sock_rx) = ws.split();
@@ -37,28 +47,67 @@ pub async fn handle(
     let proc_rx_and_cache = stream::iter(cache).chain(proc_rx);
 
     let proc_to_sock = proc_rx_and_cache
-        .filter_map(|line| ready(line.ok()))
+        .filter_map(|line| {
+            ready(match line {
+                Ok(line) => {
+                    // Process may be waiting for this client to catch up
+                    if lag == Lag::Block {
+                        drained.notify_one();
+                    }
+                    Some(line)
+                }
+                Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                    on_lag(skipped);
+                    // Close connection to lagging client
+                    (lag == Lag::Disconnect).then(|| {
+                        let close_msg = Message::close_with(CLOSE_POLICY, "client too slow");
+                        (Header::broadcast(), close_msg)
+                    })
+                }
+            })
+        })
         .filter_map(|(id, msg)| {
             ready(match id {
+                // message excludes us
+                Header {
+                    except: Some(except),
+                    ..
+                } if except.contains(conn) => None,
                 // message is routed to us
-                Header { to: Some(id), .. } if id == conn => Some(msg),
+                Header { to: Some(to), .. } if to.contains(conn) => Some(msg),
                 // message is not routed to us
                 Header { to: Some(_), .. } => None,
                 // message is broadcast
                 Header { to: None, .. } => Some(msg),
             })
         })
+        // forward until and including close message from process
+        .scan(false, |is_closed, msg| {
+            if *is_closed {
+                return ready(None);
+            }
+            *is_closed = msg.is_close();
+            ready(Some(msg))
+        })
         .map(Ok)
-        .forward(sock_tx);
+        .forward(sock_tx)
+        .map(|result| {
+            Err::<(), AppError>(match result {
+                Ok(_) => AppError::StreamClosed("process"),
+                Err(_) => AppError::StreamError("process to socket"),
+            })
+        });
 
     // forward socket to process, until closed
     let sock_to_proc = {
-        let proc_tx_sink = UnboundedSenderSink::from(proc_tx.clone());
+        let proc_tx_sink = PollSender::new(proc_tx.clone()).sink_map_err(|_| SinkError::SendFailed);
         async move {
             // forward until close message from client
             let result = sock_rx
                 .try_take_while(|msg| ready(Ok(!msg.is_close())))
                 .filter_map(|line| ready(line.ok()))
+                // ping and pong frames carry no message for the process
+                .filter(|msg| ready(msg.is_text() || msg.is_binary()))
                 .map(|msg| serialize(msg, conn, framing.socket_to_process()))
                 .forward(proc_tx_sink)
                 .await;
@@ -70,8 +119,11 @@ pub async fn handle(
         }
     };
 
-    // exit in case receiver is dropped (process::handle exited)
-    let proc_exit = proc_tx.closed().map(|_| Err::<(), ()>(()));
+    // exit in case receiver is dropped (process::handle exited) without a close message
+    let proc_exit = proc_tx
+        .closed()
+        .then(|_| sleep(PROCESS_CLOSE_GRACE))
+        .map(|_| Err::<(), ()>(()));
 
     // await barrier to let process::handle spawn child
     let proc_ready = async {
@@ -87,7 +139,7 @@ pub async fn handle(
 
     if let Err(e) = try_join!(
         sock_to_proc,
-        proc_to_sock.map_err(|_| AppError::StreamError("process to socket")),
+        proc_to_sock,
         proc_exit.map_err(|_| AppError::ChannelError("process to socket")),
         proc_ready.map_err(|_| AppError::StreamError("due to spawn failure")),
     ) {
@@ -99,6 +151,10 @@ pub async fn handle(
             _ => unreachable!(),
         }
     }
+    // Process no longer waits for this client
+    if lag == Lag::Block {
+        drained.notify_one();
+    }
     tracing::debug!(id = conn, "connection handler done");
 
     Ok(())
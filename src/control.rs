@@ -0,0 +1,75 @@
+use {
+    std::io::{Error as IOError, Result as IOResult},
+    std::os::fd::{AsRawFd, OwnedFd, RawFd},
+    std::os::unix::net::UnixStream as StdUnixStream,
+    tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    tokio::net::{UnixStream, unix::OwnedWriteHalf},
+    tokio::process::Command,
+    tokio_stream::{StreamExt, wrappers::LinesStream, wrappers::UnboundedReceiverStream},
+};
+
+use crate::types::{ControlRx, FromProcessErrAny};
+
+/// File descriptor of the control channel in the child
+pub const CONTROL_FD: RawFd = 3;
+
+/// Parent end of the control channel to a child
+pub struct Control {
+    /// Lines written by the child
+    pub rx: FromProcessErrAny,
+    /// Writer for lines read by the child
+    pub tx: OwnedWriteHalf,
+}
+
+/// Create a control channel, passed to the child as `CONTROL_FD` when spawned
+///
+/// The returned descriptor is the child end, which must stay open while children are spawned.
+pub fn open(cmd: &mut Command) -> IOResult<(Control, OwnedFd)> {
+    let (parent, child) = StdUnixStream::pair()?;
+    parent.set_nonblocking(true)?;
+    let (rx, tx) = UnixStream::from_std(parent)?.into_split();
+    let rx = LinesStream::new(BufReader::new(rx).lines());
+
+    let child = OwnedFd::from(child);
+    let fd = child.as_raw_fd();
+    cmd.env("CONTROL_FD", CONTROL_FD.to_string());
+
+    // SAFETY: dup2 and fcntl are async-signal-safe, and the closure does not allocate
+    unsafe {
+        cmd.pre_exec(move || {
+            // dup2 clears close-on-exec, except when the descriptors are equal
+            let result = match fd {
+                CONTROL_FD => libc::fcntl(fd, libc::F_SETFD, 0),
+                _ => libc::dup2(fd, CONTROL_FD),
+            };
+            match result {
+                -1 => Err(IOError::last_os_error()),
+                _ => Ok(()),
+            }
+        });
+    }
+
+    Ok((
+        Control {
+            rx: Box::new(rx),
+            tx,
+        },
+        child,
+    ))
+}
+
+/// Write server events to the child as JSON lines, until the event senders are dropped
+///
+/// Runs separately from the process handler, so that a child not reading its control channel does not block output.
+pub async fn write_events(mut tx: OwnedWriteHalf, events: ControlRx) {
+    let mut events = UnboundedReceiverStream::new(events);
+
+    while let Some(event) = events.next().await {
+        let mut line = serde_json::to_string(&event).expect("serializable event");
+        line.push('\n');
+        if let Err(e) = tx.write_all(line.as_bytes()).await {
+            tracing::debug!("failed to write control event: {}", e);
+            break;
+        }
+    }
+}
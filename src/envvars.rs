@@ -1,4 +1,8 @@
-use {std::collections::HashMap, std::net::SocketAddr, urlencoding::encode};
+use {
+    std::collections::{BTreeMap, HashMap},
+    std::net::SocketAddr,
+    urlencoding::encode,
+};
 
 use crate::types::ConnID;
 
@@ -12,6 +16,21 @@ impl Env {
     pub fn set_room(&mut self, room: &str) {
         self.cgi.room = Some(room.to_string());
     }
+
+    /// Variables of the connection, named as in templates
+    pub fn vars(&self) -> BTreeMap<String, String> {
+        let cgi_vars: HashMap<_, _> = self.cgi.clone().into();
+        let query_vars = self.query.clone().keys_upper();
+
+        cgi_vars
+            .into_iter()
+            .chain(
+                query_vars
+                    .into_iter()
+                    .map(|(k, v)| (format!("QUERY_{k}"), v)),
+            )
+            .collect()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -51,12 +70,16 @@ impl CGIEnv {
 
 impl From<CGIEnv> for HashMap<String, String> {
     fn from(env: CGIEnv) -> Self {
-        HashMap::from([
+        let mut vars = HashMap::from([
             // NOTE: implicit uppercase
             ("QUERY_STRING".to_string(), env.query_string),
             ("REMOTE_ADDR".to_string(), env.remote_addr),
-            ("ROOM".to_string(), env.room.expect("room to be defined")),
-        ])
+        ]);
+        // NOTE: pooled processes are spawned before the room is known
+        if let Some(room) = env.room {
+            vars.insert("ROOM".to_string(), room);
+        }
+        vars
     }
 }
 
@@ -144,4 +167,17 @@ mod tests {
 
         assert_eq!(result, "test 127.0.0.1:1234 foo= %23SOMETHING");
     }
+
+    #[test]
+    fn test_vars() {
+        let env = Env {
+            cgi: create_cgi(),
+            query: create_query(),
+        };
+
+        let vars = env.vars();
+
+        assert_eq!(vars.get("QUERY_STRING"), Some(&"foo=".to_string()));
+        assert_eq!(vars.get("QUERY_FOO"), Some(&"bar baz".to_string()));
+    }
 }
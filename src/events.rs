@@ -1,11 +1,14 @@
 use {
     futures::{FutureExt, TryFutureExt},
     id_pool::IdPool as PortPool,
-    std::collections::{HashMap, HashSet},
+    std::collections::HashMap,
     std::sync::Arc,
     std::sync::Mutex,
     std::sync::atomic::{AtomicU32, Ordering},
     tokio::sync::Barrier,
+    tokio::task::{AbortHandle, Id as TaskID},
+    tokio::time::{Duration, sleep},
+    tokio_util::task::TaskTracker,
     tracing::{Instrument, instrument},
     warp::ws::{Message, WebSocket},
 };
@@ -14,23 +17,38 @@ use crate::{
     channel::Channel,
     cli::Config,
     connection,
-    envvars::{Env, replace_template_env},
+    envvars::{CGIEnv, Env, replace_template_env},
     error::AppResult,
     metrics::Metrics,
     process,
-    types::{CacheBuffer, ConnID, Event, EventRx, EventTx, PortID, ProcessSenders, RoomID},
+    types::{
+        BindTx, CacheBuffer, ConnID, ControlEvent, ControlTx, Event, EventRx, EventTx, ExitReason,
+        LifecycleTx, PortID, ProcessSenders, RoomID, SharedGroups,
+    },
 };
 
-type ConnectionMap = HashMap<RoomID, HashSet<ConnID>>;
+type ConnectionMap = HashMap<RoomID, HashMap<ConnID, Env>>;
 type ProcessMap = HashMap<RoomID, ProcessSenders>;
+type ConnProcessMap = HashMap<ConnID, ProcessSenders>;
 type ProcessCacheMap = HashMap<RoomID, Arc<Mutex<CacheBuffer>>>;
+type GroupMap = HashMap<RoomID, SharedGroups>;
+type IdleTimerMap = HashMap<RoomID, AbortHandle>;
+type JoinExitMap = HashMap<ConnID, u32>;
+type ProcessPool = Vec<(ProcessSenders, BindTx)>;
 
 struct State {
     pub conns_next_id: AtomicU32,
     pub conns: ConnectionMap,
     pub cache: ProcessCacheMap,
     pub procs: ProcessMap,
+    pub conn_procs: ConnProcessMap,
+    pub groups: GroupMap,
+    pub idle: IdleTimerMap,
+    /// Exit count of the serving process when each client joined, see `restart`
+    pub join_exits: JoinExitMap,
+    pub pool: ProcessPool,
     pub ports: Option<PortPool>,
+    pub tasks: TaskTracker,
     pub cfg: Config,
 }
 
@@ -42,11 +60,31 @@ pub async fn handle(
     metrics: Metrics,
 ) -> Result<(), ()> {
     let is_oneshot = config.oneshot;
+    let is_per_conn = config.per_conn;
     let max_procs = config.max_rooms.unwrap_or(usize::MAX);
     let mut state = State::new(config);
 
+    fill_pool(&tx, &mut state);
+    metrics.set_pool_size(state.pool.len());
+
     while let Some(event) = rx.recv().await {
         match event {
+            Event::Connect { room, ws, env } if is_per_conn => {
+                if state.conn_procs.len() >= max_procs {
+                    tracing::warn!("client rejected, maximum number of processes reached");
+                    let _ = ws.close().await;
+                    continue;
+                }
+
+                metrics.inc_ws_connections(&room);
+                let conn = state.new_conn_id();
+                let spawn_barrier = Some(Arc::new(Barrier::new(2)));
+                let attach_barrier = spawn_barrier.clone();
+
+                spawn(&room, Some(conn), &env, &tx, &mut state, spawn_barrier).ok();
+                metrics.set_groups(&room, room_groups(&room, &mut state));
+                attach(room, conn, env, ws, &tx, &mut state, attach_barrier);
+            }
             Event::Connect { room, ws, env } if state.procs.contains_key(&room) => {
                 if is_oneshot {
                     tracing::warn!("client rejected, no connections permitted in oneshot mode");
@@ -54,8 +92,15 @@ pub async fn handle(
                     continue;
                 }
 
+                if let Some(timer) = state.idle.remove(&room) {
+                    tracing::info!(room, "client reconnected to idle room");
+                    timer.abort();
+                    metrics.set_idle(&room, false);
+                }
+
                 metrics.inc_ws_connections(&room);
-                attach(room, env, ws, &tx, &mut state, None);
+                let conn = state.new_conn_id();
+                attach(room, conn, env, ws, &tx, &mut state, None);
             }
             Event::Connect { room, ws, env } => {
                 if state.procs.len() >= max_procs {
@@ -68,35 +113,86 @@ pub async fn handle(
                 let spawn_barrier = Some(Arc::new(Barrier::new(2)));
                 let attach_barrier = spawn_barrier.clone();
 
-                spawn(&room, &env, &tx, &mut state, spawn_barrier).ok();
-                attach(room, env, ws, &tx, &mut state, attach_barrier);
+                if !claim(&room, &env, &mut state, spawn_barrier.clone()) {
+                    spawn(&room, None, &env, &tx, &mut state, spawn_barrier).ok();
+                }
+                metrics.set_groups(&room, room_groups(&room, &mut state));
+                let conn = state.new_conn_id();
+                attach(room, conn, env, ws, &tx, &mut state, attach_barrier);
+
+                fill_pool(&tx, &mut state);
+                metrics.set_pool_size(state.pool.len());
             }
             Event::Disconnect { room, conn, env } => {
                 metrics.dec_ws_connections(&room);
-                disconnect(room, env, conn, &mut state);
+                disconnect(room.clone(), env, conn, &tx, &mut state);
+
+                if state.idle.contains_key(&room) {
+                    metrics.set_idle(&room, true);
+                }
 
                 if is_oneshot {
                     break;
                 }
             }
-            Event::ProcessExit { room, code, port } => {
-                metrics.clear(&room);
-                exit(room, code, port, &mut state);
+            Event::ProcessExit {
+                room,
+                conn,
+                code,
+                reason,
+                port,
+            } => {
+                // Room of a per-connection process may have other connections left
+                if conn.is_none() || state.conns.get(&room).is_none_or(HashMap::is_empty) {
+                    metrics.clear(&room);
+                }
+                metrics.inc_process_exits(reason);
+                exit(room, conn, code, reason, port, &mut state);
+                metrics.set_pool_size(state.pool.len());
+
+                if state.pool.len() < state.cfg.pool.unwrap_or_default() {
+                    schedule_pool_refill(&tx, &state);
+                }
 
                 if is_oneshot {
                     break;
                 }
             }
+            Event::ProcessRestart {
+                room,
+                conn,
+                code,
+                reason,
+                attempt,
+            } => {
+                metrics.inc_process_exits(reason);
+                restart(room, conn, code, attempt, &mut state);
+            }
+            Event::ClientLag {
+                room,
+                conn,
+                skipped,
+            } => {
+                tracing::warn!(room, id = conn, skipped, "client lagging behind process");
+                metrics.inc_ws_lagged(&room);
+            }
+            Event::IdleTimeout { room, timer } => {
+                idle_timeout(room, timer, &mut state);
+            }
             Event::ProcessMeta { room, value } => {
                 metrics.set_metadata(&room, value);
             }
+            Event::PoolRefill => {
+                fill_pool(&tx, &mut state);
+                metrics.set_pool_size(state.pool.len());
+            }
             Event::Shutdown => {
                 break;
             }
         }
     }
 
-    shutdown(state);
+    shutdown(state).await;
 
     // Stop upon event handler termination
     Err(())
@@ -108,8 +204,14 @@ impl State {
             conns_next_id: AtomicU32::new(1),
             conns: HashMap::new(),
             procs: HashMap::new(),
+            conn_procs: HashMap::new(),
+            groups: HashMap::new(),
+            idle: HashMap::new(),
+            join_exits: HashMap::new(),
+            pool: Vec::new(),
             ports: cfg.tcp_ports.clone().map(PortPool::new_ranged),
             cache: HashMap::new(),
+            tasks: TaskTracker::new(),
             cfg,
         }
     }
@@ -117,23 +219,36 @@ impl State {
     pub fn new_conn_id(&self) -> ConnID {
         self.conns_next_id.fetch_add(1, Ordering::Relaxed)
     }
+
+    /// Get the senders of the process serving the connection
+    pub fn process(&self, room: &str, conn: ConnID) -> Option<&ProcessSenders> {
+        match self.cfg.per_conn {
+            true => self.conn_procs.get(&conn),
+            false => self.procs.get(room),
+        }
+    }
 }
 
-#[instrument(name = "attach", skip(env, ws, tx, state, barrier))]
+#[instrument(name = "attach", skip(conn, env, ws, tx, state, barrier))]
 fn attach(
     room: RoomID,
+    conn: ConnID,
     env: Env,
     ws: Box<WebSocket>,
     tx: &EventTx,
     state: &mut State,
     barrier: Option<Arc<Barrier>>,
 ) {
-    let conn = state.new_conn_id();
     let framing = (&state.cfg).into();
 
     // Get process senders from map
-    let (proc_tx_broadcast, proc_tx, _) = state.procs.get(&room).expect("room not in process map");
-    let proc_rx = proc_tx_broadcast.subscribe();
+    let senders = state.process(&room, conn).expect("room not in process map");
+    let proc_rx = senders.cast_tx.subscribe();
+    let proc_tx = senders.tx.clone();
+    let lifecycle_tx = senders.lifecycle_tx.clone();
+    let control_tx = senders.control_tx.clone();
+    let drained = senders.drained.clone();
+    let exits = senders.exits.clone();
 
     // Clone process cache from map for minimal mutex contention
     let cache = match state.cache.get(&room) {
@@ -147,16 +262,43 @@ fn attach(
             .conns
             .entry(room.to_string())
             .or_default()
-            .insert(conn);
+            .insert(conn, env.clone())
+            .is_none();
 
         if is_inserted {
             tracing::info!(id = conn, "client connected");
 
-            // Inform child
+            // Inform child, with exits locked so that a join is either replayed or queued
+            let exits = exits.lock().expect("poisoned lock");
+            state.join_exits.insert(conn, *exits);
             if let Some(ref join_msg_template) = state.cfg.join_msg {
                 let join_msg = replace_template_env(join_msg_template, conn, &env);
-                let _ = proc_tx.send(Message::text(join_msg));
+                send_control(&lifecycle_tx, join_msg);
             }
+            if state.cfg.control {
+                let env = env.vars();
+                let _ = control_tx.send(ControlEvent::Join { conn, env });
+            }
+        }
+    };
+
+    let on_lag = {
+        let tx = tx.clone();
+        let room = room.clone();
+
+        move |skipped| {
+            let tx = tx.clone();
+            let room = room.clone();
+            // Lag is counted without stalling the client stream on a full event bus
+            tokio::spawn(async move {
+                let _ = tx
+                    .send(Event::ClientLag {
+                        room,
+                        conn,
+                        skipped,
+                    })
+                    .await;
+            });
         }
     };
 
@@ -168,26 +310,39 @@ fn attach(
         // Return callback for connection::handle
         move |_| {
             tracing::debug!(id = conn, "client disconnecting");
-            let _ = tx.send(Event::Disconnect { room, conn, env });
-            futures::future::ready(())
+            async move {
+                let _ = tx.send(Event::Disconnect { room, conn, env }).await;
+            }
         }
     };
 
     tokio::spawn(
-        connection::handle(*ws, conn, framing, proc_rx, proc_tx.clone(), barrier, cache)
-            .then({
-                // NOTE: we invoke on_init closure immediately...
-                on_init();
-                // NOTE: ...and then invoke a closure returning the async callback closure
-                on_disconnect()
-            })
-            .in_current_span(),
+        connection::handle(
+            *ws,
+            conn,
+            framing,
+            state.cfg.lag,
+            proc_rx,
+            proc_tx,
+            drained,
+            barrier,
+            cache,
+            on_lag,
+        )
+        .then({
+            // NOTE: we invoke on_init closure immediately...
+            on_init();
+            // NOTE: ...and then invoke a closure returning the async callback closure
+            on_disconnect()
+        })
+        .in_current_span(),
     );
 }
 
 #[instrument(name = "spawn", skip(env, tx, state, barrier))]
 fn spawn(
     room: &str,
+    conn: Option<ConnID>,
     env: &Env,
     tx: &EventTx,
     state: &mut State,
@@ -199,89 +354,253 @@ fn spawn(
         tracing::debug!("reserved port {}", port);
     }
 
-    let cache = match state.cfg.cache {
-        Some(ref c) => {
-            state
-                .cache
-                .entry(room.to_string())
-                .or_insert_with(|| Arc::new(Mutex::new(CacheBuffer::new(c))));
-            state.cache.get(room).cloned()
-        }
-        None => None,
-    };
+    let cache = room_cache(room, state);
 
     let mut proc = Channel::new(&state.cfg, port, room, env.cgi.clone(), cache);
+    proc.conn = conn;
+    proc.groups = room_groups(room, state);
     let senders = proc.take_senders();
     proc.give_sender(tx.clone());
 
-    let on_init = || {
-        // Store senders in map
-        state.procs.insert(room.to_string(), senders);
-    };
-
-    let on_kill = || {
-        let tx = tx.clone();
-        let room = room.to_string();
-
-        // Return callback for process::handle
-        move |code: Option<i32>| {
-            // if sending fails, the events::handle has already been torn down
-            let _ = tx.send(Event::ProcessExit { room, code, port });
-            Ok(())
-        }
-    };
+    if state.cfg.control {
+        send_room(room, conn, &senders.control_tx, state);
+    }
 
-    tokio::spawn(
+    state.tasks.spawn(
         process::handle(proc, barrier)
-            .map_ok_or_else(
-                move |e| {
-                    tracing::error!("{}", e);
-                    Err(e)
-                },
-                {
-                    // NOTE: we invoke on_init closure immediately...
-                    on_init();
-                    // NOTE: ...and then invoke a closure returning the callback closure
-                    on_kill()
-                },
-            )
+            .map_err(|e| tracing::error!("{}", e))
             .in_current_span(),
     );
 
+    // Store senders in map
+    match conn {
+        Some(conn) => state.conn_procs.insert(conn, senders),
+        None => state.procs.insert(room.to_string(), senders),
+    };
+
     Ok(())
 }
 
-#[instrument(name = "disconnect", skip(env, conn, state))]
-fn disconnect(room: RoomID, env: Env, conn: ConnID, state: &mut State) {
-    let room_conns = state.conns.entry(room.clone()).or_default();
+/// Assign a pooled process to the room, if one is available
+#[instrument(name = "claim", skip(env, state, barrier))]
+fn claim(room: &str, env: &Env, state: &mut State, barrier: Option<Arc<Barrier>>) -> bool {
+    // Drop pooled processes that exited while waiting
+    state.pool.retain(|(_, bind_tx)| !bind_tx.is_closed());
+
+    if state.pool.is_empty() {
+        return false;
+    }
 
+    // Child is informed by the process handler, which also informs it after restarts
+    let bind_msg = state
+        .cfg
+        .bind_msg
+        .as_ref()
+        .map(|template| replace_template_env(template, 0, env));
+
+    let cache = room_cache(room, state);
+    let groups = room_groups(room, state);
+    let barrier = barrier.unwrap_or_else(|| Arc::new(Barrier::new(1)));
+    let mut binding = (room.to_string(), bind_msg, cache, groups, barrier);
+
+    while let Some((senders, bind_tx)) = state.pool.pop() {
+        match bind_tx.send(binding) {
+            Ok(()) => {
+                tracing::debug!("claimed pooled process");
+
+                if state.cfg.control {
+                    send_room(room, None, &senders.control_tx, state);
+                }
+
+                // Store senders in map
+                state.procs.insert(room.to_string(), senders);
+                return true;
+            }
+            // Pooled process exited since the pool was checked
+            Err(returned) => binding = returned,
+        }
+    }
+
+    false
+}
+
+/// Spawn processes until the pool is full
+#[instrument(name = "pool", skip_all)]
+fn fill_pool(tx: &EventTx, state: &mut State) {
+    let pool_size = state.cfg.pool.unwrap_or_default();
+    let max_procs = state.cfg.max_rooms.unwrap_or(usize::MAX);
+
+    while state.pool.len() < pool_size && state.procs.len() + state.pool.len() < max_procs {
+        let port = state.ports.as_mut().and_then(|p| p.request_id());
+
+        if let Some(port) = port {
+            tracing::debug!("reserved port {}", port);
+        }
+
+        let mut proc = Channel::new(&state.cfg, port, "", CGIEnv::default(), None);
+        let senders = proc.take_senders();
+        let bind_tx = proc.take_bind_sender();
+        proc.give_sender(tx.clone());
+
+        state.tasks.spawn(
+            process::handle(proc, None)
+                .map_err(|e| tracing::error!("{}", e))
+                .in_current_span(),
+        );
+
+        // Store senders in pool
+        state.pool.push((senders, bind_tx));
+    }
+}
+
+/// Refill the pool after pooled processes exit, delayed to avoid a spawn loop of failing processes
+fn schedule_pool_refill(tx: &EventTx, state: &State) {
+    let tx = tx.clone();
+    let delay = Duration::from_secs(state.cfg.restart_delay);
+    tokio::spawn(async move {
+        sleep(delay).await;
+        let _ = tx.send(Event::PoolRefill).await;
+    });
+}
+
+/// Send a join or leave message to the child, ahead of queued client messages
+fn send_control(lifecycle_tx: &LifecycleTx, msg: String) {
+    let _ = lifecycle_tx.send(Message::text(msg));
+}
+
+/// Send the room and the connections served by the process to the child control channel
+fn send_room(room: &str, conn: Option<ConnID>, control_tx: &ControlTx, state: &State) {
+    let mut conns: Vec<ConnID> = state
+        .conns
+        .get(room)
+        .into_iter()
+        .flat_map(HashMap::keys)
+        .copied()
+        .filter(|id| conn.is_none_or(|conn| conn == *id))
+        .collect();
+    conns.sort_unstable();
+
+    let room = room.to_string();
+    let _ = control_tx.send(ControlEvent::Room { room, conns });
+}
+
+/// Get or create the shared message cache for the room
+fn room_cache(room: &str, state: &mut State) -> Option<Arc<Mutex<CacheBuffer>>> {
+    match state.cfg.cache {
+        Some(ref c) => {
+            state
+                .cache
+                .entry(room.to_string())
+                .or_insert_with(|| Arc::new(Mutex::new(CacheBuffer::new(c))));
+            state.cache.get(room).cloned()
+        }
+        None => None,
+    }
+}
+
+/// Get or create the connection groups of the room
+fn room_groups(room: &str, state: &mut State) -> SharedGroups {
+    state.groups.entry(room.to_string()).or_default().clone()
+}
+
+#[instrument(name = "disconnect", skip(env, conn, tx, state))]
+fn disconnect(room: RoomID, env: Env, conn: ConnID, tx: &EventTx, state: &mut State) {
     // Get process handles from map
     // TODO bug this will prevent leaving room after process has quit
-    let (_, proc_tx, _) = state.procs.get(&room).expect("room not in process map");
+    let senders = state.process(&room, conn).expect("room not in process map");
+    let lifecycle_tx = senders.lifecycle_tx.clone();
+    let control_tx = senders.control_tx.clone();
+
+    let room_conns = state.conns.entry(room.clone()).or_default();
 
-    let is_removed = room_conns.remove(&conn);
+    let is_removed = room_conns.remove(&conn).is_some();
 
     if is_removed {
         tracing::info!(id = conn, "client disconnected");
+        state.join_exits.remove(&conn);
+
+        if let Some(groups) = state.groups.get(&room) {
+            groups.write().expect("poisoned lock").remove_conn(conn);
+        }
 
         // Inform child
         if let Some(ref leave_msg_template) = state.cfg.leave_msg {
             let leave_msg = replace_template_env(leave_msg_template, conn, &env);
-            let _ = proc_tx.send(Message::text(leave_msg));
+            send_control(&lifecycle_tx, leave_msg);
+        }
+        if state.cfg.control {
+            let env = env.vars();
+            let _ = control_tx.send(ControlEvent::Leave { conn, env });
+        }
+    }
+
+    if state.cfg.per_conn {
+        if let Some(senders) = state.conn_procs.remove(&conn)
+            && senders.kill_tx.send(()).is_ok()
+        {
+            // Only log if kill was sent
+            tracing::info!("client disconnected, killing process");
+        }
+        return;
+    }
+
+    if !room_conns.is_empty() {
+        return;
+    }
+
+    if let Some(linger) = state.cfg.linger
+        && state.procs.contains_key(&room)
+    {
+        tracing::info!("all clients disconnected, room is idle");
+
+        let tx = tx.clone();
+        let timer = tokio::spawn({
+            let room = room.clone();
+            async move {
+                sleep(Duration::from_secs(linger)).await;
+                let timer = tokio::task::id();
+                let _ = tx.send(Event::IdleTimeout { room, timer }).await;
+            }
+        });
+        if let Some(previous) = state.idle.insert(room.clone(), timer.abort_handle()) {
+            previous.abort();
         }
+        return;
     }
 
-    if room_conns.is_empty()
-        && let Some((_, _, kill_tx)) = state.procs.remove(&room)
-        && kill_tx.send(()).is_ok()
+    if let Some(senders) = state.procs.remove(&room)
+        && senders.kill_tx.send(()).is_ok()
     {
         // Only log if kill was sent
         tracing::info!("all clients disconnected, killing process");
     }
 }
 
-#[instrument(name = "exit", skip(code, port, state))]
-fn exit(room: RoomID, code: Option<i32>, port: Option<PortID>, state: &mut State) {
+#[instrument(name = "idle", skip(timer, state))]
+fn idle_timeout(room: RoomID, timer: TaskID, state: &mut State) {
+    // Ignore timers that were cancelled after firing
+    if state.idle.get(&room).map(|t| t.id()) != Some(timer) {
+        return;
+    }
+    state.idle.remove(&room);
+
+    if let Some(senders) = state.procs.remove(&room)
+        && senders.kill_tx.send(()).is_ok()
+    {
+        // Only log if kill was sent
+        tracing::info!("room idle timeout expired, killing process");
+    }
+}
+
+#[instrument(name = "exit", skip(conn, code, reason, port, state))]
+fn exit(
+    room: RoomID,
+    conn: Option<ConnID>,
+    code: Option<i32>,
+    reason: ExitReason,
+    port: Option<PortID>,
+    state: &mut State,
+) {
     if let Some(port) = port {
         let _ = state.ports.as_mut().map(|p| p.return_id(port));
         tracing::debug!("released port {}", port);
@@ -291,27 +610,121 @@ fn exit(room: RoomID, code: Option<i32>, port: Option<PortID>, state: &mut State
         state.cache.remove(&room);
     }
 
-    if state.procs.contains_key(&room) {
-        tracing::error!(room, code, "process exited");
-        // TODO inform clients
+    // Room of a per-connection process may have other connections left
+    if conn.is_none() || state.conns.get(&room).is_none_or(HashMap::is_empty) {
+        state.groups.remove(&room);
+    }
+
+    // Pooled process exited before it was assigned a room
+    state.pool.retain(|(_, bind_tx)| !bind_tx.is_closed());
+
+    // Process still serving clients, or an idle room, exited unexpectedly
+    let is_serving = match conn {
+        Some(conn) => state.conn_procs.contains_key(&conn),
+        None => state.procs.contains_key(&room),
+    };
+
+    // Process of an idle room has no clients left to disconnect it
+    if let Some(timer) = state.idle.remove(&room) {
+        timer.abort();
+        state.procs.remove(&room);
+    }
+
+    match reason {
+        ExitReason::Exited if is_serving => {
+            tracing::error!(room, code, "process exited");
+        }
+        ExitReason::Exited => {}
+        ExitReason::Stopped => tracing::info!(room, code, "process stopped"),
+        ExitReason::Killed => tracing::warn!(room, "process killed after stop timeout"),
+        ExitReason::Limit(limit) => tracing::error!(room, code, "process exceeded {} limit", limit),
+    }
+}
+
+#[instrument(name = "restart", skip(conn, code, attempt, state))]
+fn restart(room: RoomID, conn: Option<ConnID>, code: Option<i32>, attempt: u32, state: &mut State) {
+    tracing::warn!(room, code, attempt, "process exited, restarting");
+
+    // Get process handles from map
+    let senders = match conn {
+        Some(conn) => state.conn_procs.get(&conn),
+        None => state.procs.get(&room),
+    };
+    let Some(ProcessSenders {
+        lifecycle_tx,
+        control_tx,
+        ..
+    }) = senders
+    else {
+        return;
+    };
+
+    if state.cfg.control {
+        send_room(&room, conn, control_tx, state);
+    }
+
+    // Clients that joined after the exit have their join queued for the new child already
+    let is_replayed = |id: &ConnID| {
+        conn.is_none_or(|conn| conn == *id)
+            && state
+                .join_exits
+                .get(id)
+                .is_none_or(|exits| *exits < attempt)
+    };
+
+    // Inform new child of connected clients, or of its own client
+    if let Some(ref join_msg_template) = state.cfg.join_msg
+        && let Some(room_conns) = state.conns.get(&room)
+    {
+        let served = room_conns.iter().filter(|(id, _)| is_replayed(id));
+        for (conn, env) in served {
+            let join_msg = replace_template_env(join_msg_template, *conn, env);
+            send_control(lifecycle_tx, join_msg);
+        }
+    }
+
+    // Inform new child of the environment of its clients
+    if state.cfg.control
+        && let Some(room_conns) = state.conns.get(&room)
+    {
+        let served = room_conns.iter().filter(|(id, _)| is_replayed(id));
+        for (conn, env) in served {
+            let (conn, env) = (*conn, env.vars());
+            let _ = control_tx.send(ControlEvent::Join { conn, env });
+        }
     }
 }
 
 #[instrument(name = "shutdown", skip_all)]
-fn shutdown(state: State) {
-    tracing::debug!("killing processes");
+async fn shutdown(state: State) {
+    tracing::debug!("stopping processes");
+
+    let procs = state
+        .procs
+        .into_values()
+        .chain(state.conn_procs.into_values());
+    for senders in procs {
+        let _ = senders.kill_tx.send(());
+    }
 
-    let procs = state.procs.into_values();
-    for (_, _, kill_tx) in procs {
-        let _ = kill_tx.send(());
+    for (senders, _) in state.pool {
+        let _ = senders.kill_tx.send(());
     }
+
+    for timer in state.idle.into_values() {
+        timer.abort();
+    }
+
+    // Wait for processes to stop, including ones stopped before shutdown
+    state.tasks.close();
+    state.tasks.wait().await;
 }
 
 #[cfg(test)]
 mod tests {
 
     use std::{
-        collections::{HashMap, HashSet},
+        collections::HashMap,
         sync::{Arc, Mutex, atomic::AtomicU32},
     };
 
@@ -321,12 +734,14 @@ mod tests {
         mpsc::{self},
         oneshot,
     };
+    use tokio_util::task::TaskTracker;
     use warp::{Filter, filters::ws::Message};
 
-    use super::{Env, Event, State, attach, disconnect};
+    use super::{Env, Event, State, attach, claim, disconnect, idle_timeout, restart};
     use crate::{
         cli::Config,
-        types::{Cache, CacheBuffer, ProcessSenders, ToProcessRx},
+        message::Address,
+        types::{Cache, CacheBuffer, ControlEvent, LifecycleRx, ProcessSenders, SharedGroups},
     };
 
     fn create_config(args: &'static str) -> Config {
@@ -337,19 +752,27 @@ mod tests {
         create_process().1
     }
 
-    fn create_process() -> (ToProcessRx, ProcessSenders) {
-        let (proc_tx, proc_rx) = mpsc::unbounded_channel();
-        let broadcast_tx = broadcast::Sender::new(16);
+    fn create_process() -> (LifecycleRx, ProcessSenders) {
+        let (proc_tx, _) = mpsc::channel(16);
+        let (lifecycle_tx, lifecycle_rx) = mpsc::unbounded_channel();
         let (kill_tx, _) = oneshot::channel();
-        (proc_rx, (broadcast_tx, proc_tx, kill_tx))
+        let (control_tx, _) = mpsc::unbounded_channel();
+        let senders = ProcessSenders {
+            cast_tx: broadcast::Sender::new(16),
+            tx: proc_tx,
+            lifecycle_tx,
+            kill_tx,
+            control_tx,
+            drained: Arc::new(sync::Notify::new()),
+            exits: Arc::new(Mutex::new(0)),
+        };
+        (lifecycle_rx, senders)
     }
 
-    fn create_process_with_cache() -> (ToProcessRx, ProcessSenders, CacheBuffer) {
-        let (proc_tx, proc_rx) = mpsc::unbounded_channel();
-        let broadcast_tx = broadcast::Sender::new(16);
-        let (kill_tx, _) = oneshot::channel();
+    fn create_process_with_cache() -> (LifecycleRx, ProcessSenders, CacheBuffer) {
+        let (lifecycle_rx, senders) = create_process();
         let cache = CacheBuffer::new(&Cache::All(8));
-        (proc_rx, (broadcast_tx, proc_tx, kill_tx), cache)
+        (lifecycle_rx, senders, cache)
     }
 
     async fn create_ws() -> (warp::ws::WebSocket, warp::test::WsClient) {
@@ -379,14 +802,21 @@ mod tests {
             conns: HashMap::new(),
             procs: HashMap::from([("room1".to_string(), senders)]),
             cfg: create_config("scalesocket cat --joinmsg=foo"),
+            conn_procs: HashMap::new(),
+            groups: HashMap::new(),
+            idle: HashMap::new(),
+            join_exits: HashMap::new(),
+            pool: Vec::new(),
             ports: None,
             cache: HashMap::new(),
+            tasks: TaskTracker::new(),
         };
-        let (tx, _) = sync::mpsc::unbounded_channel::<Event>();
+        let (tx, _) = sync::mpsc::channel::<Event>(16);
         let (ws, _) = create_ws().await;
 
         attach(
             "room1".to_string(),
+            1,
             Env::default(),
             Box::new(ws),
             &tx,
@@ -405,14 +835,21 @@ mod tests {
             conns: HashMap::new(),
             procs: HashMap::from([("room1".to_string(), senders)]),
             cfg: create_config("scalesocket cat --joinmsg=foo"),
+            conn_procs: HashMap::new(),
+            groups: HashMap::new(),
+            idle: HashMap::new(),
+            join_exits: HashMap::new(),
+            pool: Vec::new(),
             ports: None,
             cache: HashMap::new(),
+            tasks: TaskTracker::new(),
         };
-        let (tx, _) = sync::mpsc::unbounded_channel::<Event>();
+        let (tx, _) = sync::mpsc::channel::<Event>(16);
         let (ws, _) = create_ws().await;
 
         attach(
             "room1".to_string(),
+            1,
             Env::default(),
             Box::new(ws),
             &tx,
@@ -421,7 +858,7 @@ mod tests {
         );
 
         let received_msg = proc_rx.recv().await.unwrap();
-        let received_msg = std::str::from_utf8(&received_msg.as_bytes()).unwrap();
+        let received_msg = std::str::from_utf8(received_msg.as_bytes()).unwrap();
         assert_eq!("foo", received_msg);
     }
 
@@ -429,22 +866,30 @@ mod tests {
     async fn test_attach_sends_cache() {
         let (_proc_rx, senders, mut cache) = create_process_with_cache();
 
-        cache.write(Message::text("foo"));
-        cache.write(Message::text("bar"));
+        cache.write(Message::text("foo").broadcast());
+        cache.write(Message::text("secret").to(2));
+        cache.write(Message::text("bar").to(1));
 
         let mut state = State {
             conns_next_id: AtomicU32::new(1),
             conns: HashMap::new(),
             procs: HashMap::from([("room1".to_string(), senders)]),
             cfg: create_config("scalesocket --cache=all:64 --joinmsg=baz cat"),
+            conn_procs: HashMap::new(),
+            groups: HashMap::new(),
+            idle: HashMap::new(),
+            join_exits: HashMap::new(),
+            pool: Vec::new(),
             ports: None,
             cache: HashMap::from([("room1".to_string(), Arc::new(Mutex::new(cache)))]),
+            tasks: TaskTracker::new(),
         };
-        let (tx, _) = sync::mpsc::unbounded_channel::<Event>();
+        let (tx, _) = sync::mpsc::channel::<Event>(16);
         let (ws, mut wsc) = create_ws().await;
 
         attach(
             "room1".to_string(),
+            1,
             Env::default(),
             Box::new(ws),
             &tx,
@@ -461,18 +906,254 @@ mod tests {
         let mut state = State {
             conns_next_id: AtomicU32::new(1),
             conns: HashMap::from([
-                ("room1".to_string(), HashSet::from([1])),
-                ("room2".to_string(), HashSet::from([2])),
+                ("room1".to_string(), HashMap::from([(1, Env::default())])),
+                ("room2".to_string(), HashMap::from([(2, Env::default())])),
             ]),
             procs: HashMap::from([("room1".to_string(), create_process_senders())]),
             cfg: create_config("scalesocket cat"),
+            conn_procs: HashMap::new(),
+            groups: HashMap::new(),
+            idle: HashMap::new(),
+            join_exits: HashMap::new(),
+            pool: Vec::new(),
             ports: None,
             cache: HashMap::new(),
+            tasks: TaskTracker::new(),
         };
 
-        disconnect("room1".to_string(), Env::default(), 1, &mut state);
+        let (tx, _) = sync::mpsc::channel::<Event>(16);
+
+        disconnect("room1".to_string(), Env::default(), 1, &tx, &mut state);
 
         assert!(state.conns.get("room1").unwrap().is_empty());
         assert!(!state.conns.get("room2").unwrap().is_empty());
     }
+
+    #[tokio::test]
+    async fn test_disconnect_removes_conn_from_groups() {
+        let groups = SharedGroups::default();
+        groups.write().unwrap().add("red", 1);
+        groups.write().unwrap().add("red", 2);
+
+        let mut state = State {
+            conns_next_id: AtomicU32::new(1),
+            conns: HashMap::from([(
+                "room1".to_string(),
+                HashMap::from([(1, Env::default()), (2, Env::default())]),
+            )]),
+            procs: HashMap::from([("room1".to_string(), create_process_senders())]),
+            cfg: create_config("scalesocket cat"),
+            conn_procs: HashMap::new(),
+            groups: HashMap::from([("room1".to_string(), groups.clone())]),
+            idle: HashMap::new(),
+            join_exits: HashMap::new(),
+            pool: Vec::new(),
+            ports: None,
+            cache: HashMap::new(),
+            tasks: TaskTracker::new(),
+        };
+
+        let (tx, _) = sync::mpsc::channel::<Event>(16);
+
+        disconnect("room1".to_string(), Env::default(), 1, &tx, &mut state);
+
+        assert_eq!(groups.read().unwrap().members("red"), vec![2]);
+    }
+
+    #[tokio::test]
+    async fn test_restart_replays_joinmsg() {
+        let (mut proc_rx, senders) = create_process();
+        let mut state = State {
+            conns_next_id: AtomicU32::new(3),
+            conns: HashMap::from([(
+                "room1".to_string(),
+                HashMap::from([(1, Env::default()), (2, Env::default())]),
+            )]),
+            procs: HashMap::from([("room1".to_string(), senders)]),
+            cfg: create_config("scalesocket cat --joinmsg=join#ID"),
+            conn_procs: HashMap::new(),
+            groups: HashMap::new(),
+            idle: HashMap::new(),
+            join_exits: HashMap::new(),
+            pool: Vec::new(),
+            ports: None,
+            cache: HashMap::new(),
+            tasks: TaskTracker::new(),
+        };
+
+        restart("room1".to_string(), None, Some(1), 1, &mut state);
+
+        let mut received_msgs = vec![
+            proc_rx.recv().await.unwrap().to_str().unwrap().to_owned(),
+            proc_rx.recv().await.unwrap().to_str().unwrap().to_owned(),
+        ];
+        received_msgs.sort();
+        assert_eq!(received_msgs, vec!["join1", "join2"]);
+    }
+
+    #[tokio::test]
+    async fn test_restart_skips_clients_joined_after_exit() {
+        let (mut proc_rx, senders) = create_process();
+        let mut state = State {
+            conns_next_id: AtomicU32::new(3),
+            conns: HashMap::from([(
+                "room1".to_string(),
+                HashMap::from([(1, Env::default()), (2, Env::default())]),
+            )]),
+            procs: HashMap::from([("room1".to_string(), senders)]),
+            cfg: create_config("scalesocket cat --joinmsg=join#ID"),
+            conn_procs: HashMap::new(),
+            groups: HashMap::new(),
+            idle: HashMap::new(),
+            join_exits: HashMap::from([(1, 0), (2, 1)]),
+            pool: Vec::new(),
+            ports: None,
+            cache: HashMap::new(),
+            tasks: TaskTracker::new(),
+        };
+
+        restart("room1".to_string(), None, Some(1), 1, &mut state);
+
+        assert_eq!(proc_rx.recv().await.unwrap().to_str(), Ok("join1"));
+        assert!(proc_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_restart_sends_control_events() {
+        let (_, mut senders) = create_process();
+        let (control_tx, mut control_rx) = mpsc::unbounded_channel();
+        senders.control_tx = control_tx;
+        let mut state = State {
+            conns_next_id: AtomicU32::new(3),
+            conns: HashMap::from([(
+                "room1".to_string(),
+                HashMap::from([(1, Env::default()), (2, Env::default())]),
+            )]),
+            procs: HashMap::new(),
+            conn_procs: HashMap::from([(2, senders)]),
+            cfg: create_config("scalesocket --perconn --control cat"),
+            groups: HashMap::new(),
+            idle: HashMap::new(),
+            join_exits: HashMap::new(),
+            pool: Vec::new(),
+            ports: None,
+            cache: HashMap::new(),
+            tasks: TaskTracker::new(),
+        };
+
+        restart("room1".to_string(), Some(2), Some(1), 1, &mut state);
+
+        assert_eq!(
+            control_rx.recv().await,
+            Some(ControlEvent::Room {
+                room: "room1".to_string(),
+                conns: vec![2]
+            })
+        );
+        assert_eq!(
+            control_rx.recv().await,
+            Some(ControlEvent::Join {
+                conn: 2,
+                env: Env::default().vars()
+            })
+        );
+        assert!(control_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_kills_per_conn_process() {
+        let (mut proc_rx, mut senders) = create_process();
+        let (kill_tx, mut kill_rx) = oneshot::channel();
+        senders.kill_tx = kill_tx;
+        let mut state = State {
+            conns_next_id: AtomicU32::new(3),
+            conns: HashMap::from([(
+                "room1".to_string(),
+                HashMap::from([(1, Env::default()), (2, Env::default())]),
+            )]),
+            procs: HashMap::new(),
+            conn_procs: HashMap::from([(1, senders), (2, create_process_senders())]),
+            groups: HashMap::new(),
+            idle: HashMap::new(),
+            join_exits: HashMap::new(),
+            pool: Vec::new(),
+            cfg: create_config("scalesocket --perconn --leavemsg=leave#ID cat"),
+            ports: None,
+            cache: HashMap::new(),
+            tasks: TaskTracker::new(),
+        };
+        let (tx, _) = sync::mpsc::channel::<Event>(16);
+
+        disconnect("room1".to_string(), Env::default(), 1, &tx, &mut state);
+
+        assert_eq!(proc_rx.recv().await.unwrap().to_str(), Ok("leave1"));
+        assert!(kill_rx.try_recv().is_ok());
+        assert!(!state.conn_procs.contains_key(&1));
+        assert!(state.conn_procs.contains_key(&2));
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_lingers() {
+        let mut state = State {
+            conns_next_id: AtomicU32::new(1),
+            conns: HashMap::from([("room1".to_string(), HashMap::from([(1, Env::default())]))]),
+            procs: HashMap::from([("room1".to_string(), create_process_senders())]),
+            conn_procs: HashMap::new(),
+            groups: HashMap::new(),
+            idle: HashMap::new(),
+            join_exits: HashMap::new(),
+            pool: Vec::new(),
+            cfg: create_config("scalesocket --linger=0 cat"),
+            ports: None,
+            cache: HashMap::new(),
+            tasks: TaskTracker::new(),
+        };
+        let (tx, mut rx) = sync::mpsc::channel::<Event>(16);
+
+        disconnect("room1".to_string(), Env::default(), 1, &tx, &mut state);
+
+        assert!(state.procs.contains_key("room1"));
+        assert!(state.idle.contains_key("room1"));
+
+        let Some(Event::IdleTimeout { room, timer }) = rx.recv().await else {
+            panic!("expected IdleTimeout");
+        };
+        idle_timeout(room, timer, &mut state);
+
+        assert!(!state.procs.contains_key("room1"));
+        assert!(!state.idle.contains_key("room1"));
+    }
+
+    #[tokio::test]
+    async fn test_claim_binds_pooled_process() {
+        let (mut proc_rx, senders) = create_process();
+        let (bind_tx, bind_rx) = oneshot::channel();
+        let mut state = State {
+            conns_next_id: AtomicU32::new(1),
+            conns: HashMap::new(),
+            procs: HashMap::new(),
+            conn_procs: HashMap::new(),
+            groups: HashMap::new(),
+            idle: HashMap::new(),
+            join_exits: HashMap::new(),
+            pool: vec![(senders, bind_tx)],
+            cfg: create_config("scalesocket --pool=1 --bindmsg=bind:#ROOM cat"),
+            ports: None,
+            cache: HashMap::new(),
+            tasks: TaskTracker::new(),
+        };
+        let mut env = Env::default();
+        env.set_room("room1");
+
+        let is_claimed = claim("room1", &env, &mut state, None);
+
+        assert!(is_claimed);
+        assert!(state.pool.is_empty());
+        assert!(state.procs.contains_key("room1"));
+
+        let (room, bind_msg, _, _, _) = bind_rx.await.unwrap();
+        assert_eq!(room, "room1");
+        assert_eq!(bind_msg.as_deref(), Some("bind:room1"));
+        assert!(proc_rx.try_recv().is_err());
+    }
 }
@@ -0,0 +1,187 @@
+use {
+    std::ffi::CString,
+    std::fs,
+    std::io::{Error as IOError, Result as IOResult},
+    std::os::unix::ffi::OsStrExt,
+    std::path::PathBuf,
+    std::sync::atomic::{AtomicU32, Ordering},
+    tokio::process::Command,
+};
+
+use crate::{cli::Config, types::Limit};
+
+// Limits are bounded to u32 by the CLI, so that scaling them does not overflow
+const MEGABYTE: u64 = 1024 * 1024;
+const CPU_PERIOD: u64 = 100_000;
+
+static CGROUP_NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+/// Resource limits applied to each child process
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// CPU time in seconds
+    cpu: Option<u64>,
+    /// Address space in megabytes
+    memory: Option<u64>,
+    /// Number of open files
+    files: Option<u64>,
+    /// Number of processes for the user
+    procs: Option<u64>,
+    /// Parent cgroup for per-child cgroups
+    cgroup: Option<PathBuf>,
+    /// Memory cap of per-child cgroups in megabytes
+    cgroup_memory: Option<u64>,
+    /// CPU cap of per-child cgroups in percent of one CPU
+    cgroup_cpu: Option<u64>,
+}
+
+impl From<&Config> for Limits {
+    fn from(cfg: &Config) -> Self {
+        Self {
+            cpu: cfg.limit_cpu,
+            memory: cfg.limit_memory,
+            files: cfg.limit_files,
+            procs: cfg.limit_procs,
+            cgroup: cfg.cgroup.clone(),
+            cgroup_memory: cfg.cgroup_memory,
+            cgroup_cpu: cfg.cgroup_cpu,
+        }
+    }
+}
+
+impl Limits {
+    /// Set rlimits in the child before it executes
+    pub fn apply(&self, cmd: &mut Command) {
+        // The hard CPU limit is set one second later, so the child receives SIGXCPU before SIGKILL
+        let rlimits = [
+            (libc::RLIMIT_CPU, self.cpu.map(|s| (s, s + 1))),
+            (
+                libc::RLIMIT_AS,
+                self.memory.map(|mb| (mb * MEGABYTE, mb * MEGABYTE)),
+            ),
+            (libc::RLIMIT_NOFILE, self.files.map(|n| (n, n))),
+            (libc::RLIMIT_NPROC, self.procs.map(|n| (n, n))),
+        ];
+        let rlimits: Vec<_> = rlimits
+            .into_iter()
+            .filter_map(|(resource, limit)| limit.map(|limit| (resource, limit)))
+            .collect();
+
+        if rlimits.is_empty() {
+            return;
+        }
+
+        // SAFETY: setrlimit is async-signal-safe, and the closure does not allocate
+        unsafe {
+            cmd.pre_exec(move || {
+                for (resource, (soft, hard)) in &rlimits {
+                    let rlimit = libc::rlimit {
+                        rlim_cur: *soft as libc::rlim_t,
+                        rlim_max: *hard as libc::rlim_t,
+                    };
+                    if libc::setrlimit(*resource, &rlimit) != 0 {
+                        return Err(IOError::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// Create a cgroup for a child, if enabled
+    pub fn cgroup(&self) -> Option<IOResult<Cgroup>> {
+        let parent = self.cgroup.as_ref()?;
+        let id = CGROUP_NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let path = parent.join(format!("scalesocket-{}-{}", std::process::id(), id));
+
+        Some(Cgroup::create(path, self.cgroup_memory, self.cgroup_cpu))
+    }
+}
+
+/// A cgroup v2 containing a single child process and its descendants
+///
+/// The cgroup is removed when dropped.
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+    oom_kills: u64,
+}
+
+impl Cgroup {
+    fn create(path: PathBuf, memory: Option<u64>, cpu: Option<u64>) -> IOResult<Self> {
+        fs::create_dir(&path)?;
+
+        if let Some(memory) = memory {
+            fs::write(path.join("memory.max"), (memory * MEGABYTE).to_string())?;
+            fs::write(path.join("memory.swap.max"), "0").ok();
+        }
+        if let Some(cpu) = cpu {
+            let quota = cpu * CPU_PERIOD / 100;
+            fs::write(path.join("cpu.max"), format!("{quota} {CPU_PERIOD}"))?;
+        }
+
+        Ok(Self { path, oom_kills: 0 })
+    }
+
+    /// Move the child into the cgroup before it executes
+    pub fn attach(&self, cmd: &mut Command) -> IOResult<()> {
+        let procs = CString::new(self.path.join("cgroup.procs").as_os_str().as_bytes())?;
+
+        // SAFETY: open, write and close are async-signal-safe, and the closure does not allocate
+        unsafe {
+            cmd.pre_exec(move || {
+                let fd = libc::open(procs.as_ptr(), libc::O_WRONLY);
+                if fd < 0 {
+                    return Err(IOError::last_os_error());
+                }
+                // Writing 0 moves the writing process
+                let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+                libc::close(fd);
+                match written {
+                    1 => Ok(()),
+                    _ => Err(IOError::last_os_error()),
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Check if the memory cap was hit since the last check
+    pub fn take_oom_kill(&mut self) -> bool {
+        let events = fs::read_to_string(self.path.join("memory.events")).unwrap_or_default();
+        let oom_kills = events
+            .lines()
+            .find_map(|line| line.strip_prefix("oom_kill "))
+            .and_then(|n| n.trim().parse().ok())
+            .unwrap_or(self.oom_kills);
+
+        let is_oom_killed = oom_kills > self.oom_kills;
+        self.oom_kills = oom_kills;
+        is_oom_killed
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // Kill remaining descendants, so that the cgroup can be removed
+        let _ = fs::write(self.path.join("cgroup.kill"), "1");
+
+        if let Err(e) = fs::remove_dir(&self.path) {
+            tracing::debug!("failed to remove cgroup {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Determine which limit, if any, caused the child to exit
+///
+/// Only the CPU time rlimit and the cgroup memory cap terminate the child in a detectable way.
+/// The other rlimits make calls fail in the child, which then exits on its own.
+pub fn exceeded_limit(signal: Option<i32>, cgroup: Option<&mut Cgroup>) -> Option<Limit> {
+    if cgroup.is_some_and(|c| c.take_oom_kill()) {
+        return Some(Limit::Memory);
+    }
+    match signal {
+        Some(libc::SIGXCPU) => Some(Limit::Cpu),
+        _ => None,
+    }
+}
@@ -1,9 +1,11 @@
 mod channel;
 mod cli;
 mod connection;
+mod control;
 mod envvars;
 mod error;
 mod events;
+mod limits;
 mod logging;
 mod message;
 mod metrics;
@@ -29,7 +31,7 @@ async fn main() {
 
     setup_logging(&config);
 
-    let (tx, rx) = sync::mpsc::unbounded_channel::<Event>();
+    let (tx, rx) = sync::mpsc::channel::<Event>(config.event_buffer.get());
     let (routes_shutdown_tx, routes_shutdown_rx) = sync::oneshot::channel();
     let events_shutdown_tx = tx.clone();
 
@@ -52,6 +54,7 @@ mod tests {
     use prometheus_client::registry::Registry;
     use tokio::task::yield_now;
     use warp::test::WsClient;
+    use warp::ws::Message;
 
     use super::routes;
     use crate::cli::Config;
@@ -105,7 +108,7 @@ mod tests {
 
     #[tokio::test]
     async fn connects_to_room() {
-        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<Event>();
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<Event>(16);
         Client::connect("/example", tx).await;
 
         let received_event = rx.recv().await.unwrap();
@@ -119,7 +122,7 @@ mod tests {
 
     #[tokio::test]
     async fn stdio_e2e_echo() {
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<Event>();
+        let (tx, rx) = tokio::sync::mpsc::channel::<Event>(16);
         let config = create_config("scalesocket --oneshot echo -- hello");
         let metrics = create_metrics();
         let mut client = Client::connect("/example", tx.clone()).await;
@@ -133,7 +136,7 @@ mod tests {
 
     #[tokio::test]
     async fn stdio_e2e_framed_from() {
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<Event>();
+        let (tx, rx) = tokio::sync::mpsc::channel::<Event>(16);
         let config = create_config("scalesocket --oneshot --frame head -- -n 1");
         let metrics = create_metrics();
         let mut client = Client::connect("/example", tx.clone()).await;
@@ -149,7 +152,7 @@ mod tests {
 
     #[tokio::test]
     async fn stdio_e2e_framed_to() {
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<Event>();
+        let (tx, rx) = tokio::sync::mpsc::channel::<Event>(16);
         let config = create_config("scalesocket --oneshot --frame head -- -n 1");
         let metrics = create_metrics();
         let mut client = Client::connect("/example", tx.clone()).await;
@@ -162,4 +165,147 @@ mod tests {
         let (_, received_messages) = tokio::join!(handle, inspect);
         assert_eq!(received_messages, vec![r#"{"_from":1,"_to":1}"#]);
     }
+
+    #[tokio::test]
+    async fn stdio_e2e_framed_to_list() {
+        let (tx, rx) = tokio::sync::mpsc::channel::<Event>(16);
+        let config = create_config("scalesocket --oneshot --frame head -- -n 1");
+        let metrics = create_metrics();
+        let mut client = Client::connect("/example", tx.clone()).await;
+
+        client.send(r#"{"_to":[2,1]}"#).await;
+
+        let handle = events::handle(tx, rx, config, metrics);
+        let inspect = client.inspect_flaky();
+
+        let (_, received_messages) = tokio::join!(handle, inspect);
+        assert_eq!(received_messages, vec![r#"{"_from":1,"_to":[2,1]}"#]);
+    }
+
+    #[tokio::test]
+    async fn stdio_e2e_framed_except() {
+        let (tx, rx) = tokio::sync::mpsc::channel::<Event>(16);
+        let config = create_config("scalesocket --frame head -- -n 1");
+        let metrics = create_metrics();
+        let mut client1 = Client::connect("/example", tx.clone()).await;
+        let mut client2 = Client::connect("/example", tx.clone()).await;
+
+        client1.send(r#"{"_except":1}"#).await;
+
+        tokio::spawn(events::handle(tx, rx, config, metrics));
+
+        let expected = r#"{"_except":1,"_from":1}"#.to_string();
+        assert_eq!(client2.recv().await, Ok(expected));
+        assert_eq!(client1.recv().await, Err(()));
+    }
+
+    #[tokio::test]
+    async fn stdio_e2e_envelope_skips_ping() {
+        let (tx, rx) = tokio::sync::mpsc::channel::<Event>(16);
+        let config = create_config("scalesocket --oneshot --frame=envelope head -- -n 1");
+        let metrics = create_metrics();
+        let mut client = Client::connect("/example", tx.clone()).await;
+
+        client.inner.send(Message::ping("")).await;
+        client.send("hello").await;
+
+        let inspect = async {
+            // Skip the pong sent in reply to the ping
+            loop {
+                match client.inner.recv().await {
+                    Ok(msg) if msg.is_pong() => continue,
+                    Ok(msg) => break msg.to_str().unwrap_or_default().to_owned(),
+                    Err(_) => break String::new(),
+                }
+            }
+        };
+        let handle = events::handle(tx.clone(), rx, config, metrics);
+
+        let (_, received_message) = tokio::join!(handle, inspect);
+        assert_eq!(received_message, "hello");
+    }
+
+    #[tokio::test]
+    async fn stdio_e2e_jsonrpc() {
+        let (tx, rx) = tokio::sync::mpsc::channel::<Event>(16);
+        let config = create_config("scalesocket --frame=jsonrpc cat");
+        let metrics = create_metrics();
+        let mut client1 = Client::connect("/example", tx.clone()).await;
+        let mut client2 = Client::connect("/example", tx.clone()).await;
+
+        client2.send(r#"{"id":1,"result":"bar"}"#).await;
+        client1.send(r#"{"id":1,"result":"foo"}"#).await;
+        client1.send(r#"{"method":"tick"}"#).await;
+
+        tokio::spawn(events::handle(tx, rx, config, metrics));
+
+        assert_eq!(
+            client1.recv().await,
+            Ok(r#"{"id":1,"result":"foo"}"#.to_string())
+        );
+        assert_eq!(client1.recv().await, Ok(r#"{"method":"tick"}"#.to_string()));
+        assert_eq!(
+            client2.recv().await,
+            Ok(r#"{"id":1,"result":"bar"}"#.to_string())
+        );
+        assert_eq!(client2.recv().await, Ok(r#"{"method":"tick"}"#.to_string()));
+    }
+
+    #[tokio::test]
+    async fn stdio_e2e_kick() {
+        let (tx, rx) = tokio::sync::mpsc::channel::<Event>(16);
+        let config = create_config("scalesocket --serverframe=json cat");
+        let metrics = create_metrics();
+        let mut client1 = Client::connect("/example", tx.clone()).await;
+        let mut client2 = Client::connect("/example", tx.clone()).await;
+
+        client2.send(r#"{"_kick":1}"#).await;
+        client2.send(r#"{"foo":1}"#).await;
+
+        tokio::spawn(events::handle(tx, rx, config, metrics));
+
+        assert_eq!(client1.recv().await, Err(()));
+        assert_eq!(client2.recv().await, Ok(r#"{"foo":1}"#.to_string()));
+    }
+
+    #[tokio::test]
+    async fn stdio_e2e_exit_msg() {
+        let (tx, rx) = tokio::sync::mpsc::channel::<Event>(16);
+        let config =
+            create_config(r#"scalesocket --oneshot --exitmsg={"t":"Exit","code":#CODE} false"#);
+        let metrics = create_metrics();
+        let mut client = Client::connect("/example", tx.clone()).await;
+
+        let handle = events::handle(tx, rx, config, metrics);
+        let inspect = client.inspect_flaky();
+
+        let (_, received_messages) = tokio::join!(handle, inspect);
+        assert_eq!(received_messages, vec![r#"{"t":"Exit","code":1}"#]);
+    }
+
+    #[tokio::test]
+    async fn stdio_e2e_pool_bindmsg() {
+        let (tx, rx) = tokio::sync::mpsc::channel::<Event>(16);
+        let config = create_config("scalesocket --pool=1 --bindmsg=bound:#ROOM head -- -n 1");
+        let metrics = create_metrics();
+        let mut client = Client::connect("/example", tx.clone()).await;
+
+        tokio::spawn(events::handle(tx, rx, config, metrics));
+
+        assert_eq!(client.recv().await, Ok("bound:example".to_string()));
+    }
+
+    #[tokio::test]
+    async fn stdio_e2e_per_conn() {
+        let (tx, rx) = tokio::sync::mpsc::channel::<Event>(16);
+        let config = create_config("scalesocket --perconn --joinmsg=join#ID head -- -n 1");
+        let metrics = create_metrics();
+        let mut client1 = Client::connect("/example", tx.clone()).await;
+        let mut client2 = Client::connect("/example", tx.clone()).await;
+
+        tokio::spawn(events::handle(tx, rx, config, metrics));
+
+        assert_eq!(client1.recv().await, Ok("join1".to_string()));
+        assert_eq!(client2.recv().await, Ok("join2".to_string()));
+    }
 }
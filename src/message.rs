@@ -1,9 +1,18 @@
 use {
-    bytes::Bytes, num_derive::FromPrimitive, num_traits::FromPrimitive,
-    sender_sink::wrappers::SinkError, serde_json::Value, warp::ws::Message,
+    base64::{Engine, engine::general_purpose::STANDARD as BASE64},
+    bytes::{Buf, Bytes, BytesMut},
+    num_derive::FromPrimitive,
+    num_traits::FromPrimitive,
+    sender_sink::wrappers::SinkError,
+    serde::Deserialize,
+    serde_json::{Value, json},
+    std::borrow::Cow,
+    std::io::{Error as IOError, ErrorKind},
+    tokio_util::codec::Decoder,
+    warp::ws::Message,
 };
 
-use crate::types::{ConnID, Frame, Header};
+use crate::types::{ConnID, Frame, Header, Recipients};
 
 /// An extension trait for `Message`s that provides routing helpers
 pub trait Address<T> {
@@ -31,28 +40,59 @@ impl Address<Message> for Message {
 }
 
 /// Deserialize message coming from process
-pub fn deserialize(msg: &Bytes, frame: Option<Frame>) -> Result<(Header, &[u8]), &'static str> {
+pub fn deserialize(
+    msg: &Bytes,
+    frame: Option<Frame>,
+) -> Result<(Header, Cow<'_, [u8]>), &'static str> {
     match frame {
         Some(f) => match f {
             Frame::GWSocket => {
-                let (header, msg_type, length, payload) = parse_binary_header(msg);
-                let effective_len = payload.len();
-                let header_len = length as usize;
+                if msg.len() < HEADER_LEN {
+                    return Err("Message is shorter than header");
+                }
 
-                assert_eq!(
-                    effective_len, header_len,
-                    "Message length {effective_len} does not match {header_len}: Chunked payloads are not supported",
-                );
+                let (header, msg_type, length, payload) = parse_binary_header(msg);
+                if payload.len() != length as usize {
+                    return Err("Message length does not match header");
+                }
 
                 if msg_type.is_none() {
                     return Err("Unknown message type");
                 }
 
-                Ok((header, payload))
+                Ok((header, Cow::Borrowed(payload)))
+            }
+            Frame::LenPrefix => {
+                if msg.len() < LENPREFIX_HEADER_LEN {
+                    return Err("Message is shorter than header");
+                }
+
+                let (header, length, payload) = parse_lenprefix_header(msg);
+                if payload.len() != length as usize {
+                    return Err("Message length does not match header");
+                }
+
+                Ok((header, Cow::Borrowed(payload)))
+            }
+            Frame::Length => {
+                if msg.len() < LENGTH_HEADER_LEN {
+                    return Err("Message is shorter than header");
+                }
+
+                let (length, payload) = parse_length_header(msg);
+                if payload.len() != length as usize {
+                    return Err("Message length does not match header");
+                }
+
+                Ok((Header::broadcast(), Cow::Borrowed(payload)))
             }
+            Frame::MsgPack => Ok(parse_msgpack_header(msg)),
+            Frame::CBOR => Ok(parse_cbor_header(msg)),
             Frame::JSON => Ok(parse_json_header(msg)),
+            Frame::Envelope => parse_envelope(msg),
+            Frame::JSONRPC => parse_jsonrpc(msg),
         },
-        None => Ok((Header::broadcast(), msg)),
+        None => Ok((Header::broadcast(), Cow::Borrowed(msg))),
     }
 }
 
@@ -61,8 +101,73 @@ pub fn serialize(msg: Message, conn: ConnID, frame: Option<Frame>) -> Result<Mes
     match frame {
         Some(f) => match f {
             Frame::GWSocket => {
-                unimplemented!("Client side binary framing has not been implemented")
+                let msg_type = match msg.is_binary() {
+                    true => Type::Binary,
+                    false => Type::Text,
+                };
+                let payload = msg.as_bytes();
+                let Ok(length) = u32::try_from(payload.len()) else {
+                    tracing::error!("bad data: message is too long");
+                    return Err(SinkError::SendFailed);
+                };
+                let header = binary_header(conn, msg_type, length);
+                Ok(Message::binary([&header, payload].concat()))
             }
+            Frame::LenPrefix => {
+                let payload = msg.as_bytes();
+                let Ok(length) = u32::try_from(payload.len()) else {
+                    tracing::error!("bad data: message is too long");
+                    return Err(SinkError::SendFailed);
+                };
+                let header = lenprefix_header(conn, length);
+                Ok(Message::binary([&header, payload].concat()))
+            }
+            Frame::Length => {
+                let payload = msg.as_bytes();
+                let Ok(length) = u32::try_from(payload.len()) else {
+                    tracing::error!("bad data: message is too long");
+                    return Err(SinkError::SendFailed);
+                };
+                Ok(Message::binary([&length.to_be_bytes(), payload].concat()))
+            }
+            Frame::MsgPack => match rmpv::decode::read_value(&mut msg.as_bytes()) {
+                Ok(rmpv::Value::Map(mut map)) => {
+                    map.retain(|(k, _)| k.as_str() != Some("_from"));
+                    map.push((rmpv::Value::from("_from"), rmpv::Value::from(conn)));
+
+                    let mut data = Vec::new();
+                    rmpv::encode::write_value(&mut data, &rmpv::Value::Map(map))
+                        .map_err(|_| SinkError::SendFailed)?;
+                    Ok(Message::binary(data))
+                }
+                Ok(_) => {
+                    tracing::error!("bad data: message is not a MessagePack map");
+                    Err(SinkError::SendFailed)
+                }
+                Err(_) => {
+                    tracing::error!("bad data: message is not valid MessagePack");
+                    Err(SinkError::SendFailed)
+                }
+            },
+            Frame::CBOR => match ciborium::de::from_reader(msg.as_bytes()) {
+                Ok(ciborium::Value::Map(mut map)) => {
+                    map.retain(|(k, _)| k.as_text() != Some("_from"));
+                    map.push((ciborium::Value::from("_from"), ciborium::Value::from(conn)));
+
+                    let mut data = Vec::new();
+                    ciborium::ser::into_writer(&ciborium::Value::Map(map), &mut data)
+                        .map_err(|_| SinkError::SendFailed)?;
+                    Ok(Message::binary(data))
+                }
+                Ok(_) => {
+                    tracing::error!("bad data: message is not a CBOR map");
+                    Err(SinkError::SendFailed)
+                }
+                Err(_) => {
+                    tracing::error!("bad data: message is not valid CBOR");
+                    Err(SinkError::SendFailed)
+                }
+            },
             Frame::JSON => match serde_json::from_slice::<Value>(msg.as_bytes()) {
                 Ok(mut v) if v.is_object() => {
                     v["_from"] = Value::from(conn);
@@ -77,26 +182,222 @@ pub fn serialize(msg: Message, conn: ConnID, frame: Option<Frame>) -> Result<Mes
                     Err(SinkError::SendFailed)
                 }
             },
+            Frame::Envelope => {
+                let envelope = match msg.is_binary() {
+                    true => {
+                        let data = BASE64.encode(msg.as_bytes());
+                        json!({"_from": conn, "_binary": true, "data": data})
+                    }
+                    false => json!({"_from": conn, "data": msg.to_str().unwrap_or_default()}),
+                };
+                Ok(Message::text(envelope.to_string()))
+            }
+            Frame::JSONRPC => match serde_json::from_slice::<Value>(msg.as_bytes()) {
+                Ok(mut v) if v.is_object() => {
+                    namespace_jsonrpc_id(conn, &mut v);
+                    Ok(Message::text(v.to_string()))
+                }
+                Ok(Value::Array(mut batch)) => {
+                    batch.iter_mut().for_each(|v| namespace_jsonrpc_id(conn, v));
+                    Ok(Message::text(Value::Array(batch).to_string()))
+                }
+                Ok(_) => {
+                    tracing::error!("bad data: message is not a JSON-RPC request object");
+                    Err(SinkError::SendFailed)
+                }
+                Err(_) => {
+                    tracing::error!("bad data: message is not valid JSON");
+                    Err(SinkError::SendFailed)
+                }
+            },
         },
         None => Ok(msg),
     }
 }
 
+/// Remove the given fields from a message from the process
+///
+/// Only messages framed as maps that contain any of the fields are changed, and other messages
+/// are returned as is, keeping their original encoding and key order.
+pub fn strip_fields<'a>(
+    msg: Cow<'a, [u8]>,
+    frame: Option<Frame>,
+    fields: &[String],
+) -> Cow<'a, [u8]> {
+    let is_stripped = |k: &str| fields.iter().any(|f| f == k);
+
+    let data = match frame {
+        Some(Frame::JSON | Frame::JSONRPC) => match serde_json::from_slice::<Value>(&msg) {
+            Ok(Value::Object(mut map)) if map.keys().any(|k| is_stripped(k)) => {
+                map.retain(|k, _| !is_stripped(k));
+                serde_json::to_vec(&map).ok()
+            }
+            _ => None,
+        },
+        Some(Frame::MsgPack) => match rmpv::decode::read_value(&mut &msg[..]) {
+            Ok(rmpv::Value::Map(mut map))
+                if map.iter().any(|(k, _)| k.as_str().is_some_and(is_stripped)) =>
+            {
+                map.retain(|(k, _)| !k.as_str().is_some_and(is_stripped));
+                let mut data = Vec::new();
+                rmpv::encode::write_value(&mut data, &rmpv::Value::Map(map))
+                    .ok()
+                    .map(|_| data)
+            }
+            _ => None,
+        },
+        Some(Frame::CBOR) => match ciborium::de::from_reader(&msg[..]) {
+            Ok(ciborium::Value::Map(mut map))
+                if map
+                    .iter()
+                    .any(|(k, _)| k.as_text().is_some_and(is_stripped)) =>
+            {
+                map.retain(|(k, _)| !k.as_text().is_some_and(is_stripped));
+                let mut data = Vec::new();
+                ciborium::ser::into_writer(&ciborium::Value::Map(map), &mut data)
+                    .ok()
+                    .map(|_| data)
+            }
+            _ => None,
+        },
+        _ => None,
+    };
+
+    data.map_or(msg, Cow::Owned)
+}
+
+/// Length of the gwsocket header
+const HEADER_LEN: usize = 12;
+/// Length of the length-prefix header
+const LENPREFIX_HEADER_LEN: usize = 8;
+/// Length of the length-prefix header without a routing ID
+const LENGTH_HEADER_LEN: usize = 4;
+/// Largest payload accepted from a process
+const MAX_PAYLOAD_LEN: u32 = 64 << 20;
+
 #[derive(FromPrimitive, ToPrimitive, Debug, PartialEq)]
 pub enum Type {
     Text = 1,
     Binary = 2,
 }
 
-pub(crate) fn parse_json_header(msg: &Bytes) -> (Header, &[u8]) {
+pub(crate) fn parse_json_header(msg: &Bytes) -> (Header, Cow<'_, [u8]>) {
     (
         serde_json::from_slice::<Header>(msg).unwrap_or_default(),
-        msg,
+        Cow::Borrowed(msg),
     )
 }
 
-/// Parse fixed-length 12 byte header consisting of three u32 values in network byte order.
+pub(crate) fn parse_msgpack_header(msg: &Bytes) -> (Header, Cow<'_, [u8]>) {
+    (
+        rmp_serde::from_slice::<Header>(msg).unwrap_or_default(),
+        Cow::Borrowed(msg),
+    )
+}
+
+pub(crate) fn parse_cbor_header(msg: &Bytes) -> (Header, Cow<'_, [u8]>) {
+    (
+        ciborium::de::from_reader::<Header, _>(&msg[..]).unwrap_or_default(),
+        Cow::Borrowed(msg),
+    )
+}
+
+/// Parse the header of a JSON-RPC message, routing responses to the client that made the request
 ///
+/// The request ID of a response is restored to the one sent by the client. A batch response is routed to the
+/// client of its requests. Responses that no client is known for are rejected, and other messages are routed like JSON.
+pub(crate) fn parse_jsonrpc(msg: &Bytes) -> Result<(Header, Cow<'_, [u8]>), &'static str> {
+    let (mut header, msg) = parse_json_header(msg);
+    let v = match serde_json::from_slice::<Value>(&msg) {
+        Ok(Value::Array(mut batch)) => {
+            let mut conns = batch.iter_mut().filter_map(restore_jsonrpc_id);
+            let conn = conns
+                .next()
+                .ok_or("JSON-RPC batch has no client request ID")?;
+            if !conns.all(|other| other == conn) {
+                return Err("JSON-RPC batch has requests of several clients");
+            }
+
+            header.to = Some(Recipients::One(conn));
+            Value::Array(batch)
+        }
+        Ok(mut v) => match restore_jsonrpc_id(&mut v) {
+            Some(conn) => {
+                header.to = Some(Recipients::One(conn));
+                v
+            }
+            None if is_jsonrpc_response(&v) => {
+                return Err("JSON-RPC response has no client request ID");
+            }
+            None => return Ok((header, msg)),
+        },
+        Err(_) => return Ok((header, msg)),
+    };
+
+    Ok((header, Cow::Owned(v.to_string().into_bytes())))
+}
+
+/// Namespace the request ID of a request object, leaving notifications as is
+fn namespace_jsonrpc_id(conn: ConnID, v: &mut Value) {
+    if let Some(id) = v.get_mut("id").filter(|id| !id.is_null()) {
+        *id = jsonrpc_id(conn, id);
+    }
+}
+
+/// Restore the request ID of a response object, returning the client that made the request
+fn restore_jsonrpc_id(v: &mut Value) -> Option<ConnID> {
+    let (conn, id) = v.get("id").and_then(parse_jsonrpc_id)?;
+    v["id"] = id;
+    Some(conn)
+}
+
+/// Check if the message is a JSON-RPC response, which has a result or an error
+fn is_jsonrpc_response(v: &Value) -> bool {
+    v.get("result").is_some() || v.get("error").is_some()
+}
+
+/// Namespace the request ID of a client, to make it unique within the room
+///
+/// The ID is a string of the connection ID and the original ID as JSON, separated by a colon.
+pub(crate) fn jsonrpc_id(conn: ConnID, id: &Value) -> Value {
+    Value::from(format!("{conn}:{id}"))
+}
+
+/// Split a namespaced request ID into the connection ID and the original ID
+pub(crate) fn parse_jsonrpc_id(id: &Value) -> Option<(ConnID, Value)> {
+    let (conn, id) = id.as_str()?.split_once(':')?;
+    Some((conn.parse().ok()?, serde_json::from_str(id).ok()?))
+}
+
+/// A JSON object wrapping a message, with routing fields alongside its data
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(flatten)]
+    header: Header,
+    data: Value,
+}
+
+/// Parse the header of an envelope, and unwrap its data
+///
+/// String data is unwrapped as is, or decoded from base64 if `_binary` is set. Other data is unwrapped as JSON.
+pub(crate) fn parse_envelope(msg: &Bytes) -> Result<(Header, Cow<'_, [u8]>), &'static str> {
+    let Ok(Envelope { header, data }) = serde_json::from_slice(msg) else {
+        return Err("Message is not a valid envelope");
+    };
+    let data = match data {
+        Value::String(data) if header.is_binary => BASE64
+            .decode(data)
+            .map_err(|_| "Envelope data is not valid base64")?,
+        Value::String(data) => data.into_bytes(),
+        data => data.to_string().into_bytes(),
+    };
+    Ok((header, Cow::Owned(data)))
+}
+
+/// Parse fixed-length 12 byte header consisting of three u32 values in little-endian byte order.
+///
+/// gwsocket packs the header in host byte order, which is little-endian on the
+/// platforms it runs on.
 /// The header consists of the routing ID, message type and payload length.
 /// Message type is 1 for text and 2 for binary.
 pub(crate) fn parse_binary_header(data: &[u8]) -> (Header, Option<Type>, u32, &[u8]) {
@@ -123,17 +424,458 @@ pub(crate) fn parse_binary_header(data: &[u8]) -> (Header, Option<Type>, u32, &[
     (header, msg_type, msg_len, &data[12..])
 }
 
+/// Create fixed-length 12 byte header, see `parse_binary_header`
+pub(crate) fn binary_header(id: ConnID, msg_type: Type, msg_len: u32) -> [u8; 12] {
+    let mut header = [0; 12];
+    header[0..4].copy_from_slice(&id.to_le_bytes());
+    header[4..8].copy_from_slice(&(msg_type as u32).to_le_bytes());
+    header[8..12].copy_from_slice(&msg_len.to_le_bytes());
+    header
+}
+
+/// Parse fixed-length 8 byte header consisting of two u32 values in big-endian byte order.
+///
+/// The header consists of the payload length and routing ID.
+pub(crate) fn parse_lenprefix_header(data: &[u8]) -> (Header, u32, &[u8]) {
+    let mut msg_len_data = [0; 4];
+    msg_len_data.copy_from_slice(&data[0..4]);
+    let msg_len = u32::from_be_bytes(msg_len_data);
+
+    let mut id_data = [0; 4];
+    id_data.copy_from_slice(&data[4..8]);
+    let id = u32::from_be_bytes(id_data);
+
+    let header = match id {
+        // message is broadcast
+        0 => Header::broadcast(),
+        // message is routed
+        id => Header::to(id),
+    };
+
+    (header, msg_len, &data[8..])
+}
+
+/// Create fixed-length 8 byte header, see `parse_lenprefix_header`
+pub(crate) fn lenprefix_header(id: ConnID, msg_len: u32) -> [u8; 8] {
+    let mut header = [0; 8];
+    header[0..4].copy_from_slice(&msg_len.to_be_bytes());
+    header[4..8].copy_from_slice(&id.to_be_bytes());
+    header
+}
+
+/// Parse fixed-length 4 byte header consisting of the payload length as u32 in big-endian byte order.
+pub(crate) fn parse_length_header(data: &[u8]) -> (u32, &[u8]) {
+    let mut msg_len_data = [0; 4];
+    msg_len_data.copy_from_slice(&data[0..4]);
+    (u32::from_be_bytes(msg_len_data), &data[4..])
+}
+
+/// Decoder splitting process output into length-prefixed frames
+///
+/// Each item is a header followed by exactly the number of payload bytes given in the header.
+/// Frames longer than `MAX_PAYLOAD_LEN` are skipped.
+#[derive(Debug)]
+pub struct LenPrefixCodec {
+    header_len: usize,
+    skipping: usize,
+}
+
+impl LenPrefixCodec {
+    pub fn new(frame: Frame) -> Self {
+        let header_len = match frame {
+            Frame::LenPrefix => LENPREFIX_HEADER_LEN,
+            Frame::Length => LENGTH_HEADER_LEN,
+            _ => unreachable!("{:?} is not length-prefixed", frame),
+        };
+        Self {
+            header_len,
+            skipping: 0,
+        }
+    }
+}
+
+impl Decoder for LenPrefixCodec {
+    type Item = Bytes;
+    type Error = IOError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Bytes>, IOError> {
+        loop {
+            // Discard the rest of an oversized frame
+            let skipped = self.skipping.min(src.len());
+            src.advance(skipped);
+            self.skipping -= skipped;
+            if self.skipping > 0 || src.len() < self.header_len {
+                return Ok(None);
+            }
+
+            let (length, _) = parse_length_header(&src[..self.header_len]);
+            let frame_len = self.header_len + length as usize;
+            if length > MAX_PAYLOAD_LEN {
+                tracing::warn!("skipping length-prefixed frame of {} bytes", length);
+                self.skipping = frame_len;
+                continue;
+            }
+
+            if src.len() < frame_len {
+                src.reserve(frame_len - src.len());
+                return Ok(None);
+            }
+            return Ok(Some(src.split_to(frame_len).freeze()));
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Bytes>, IOError> {
+        if let Some(frame) = self.decode(src)? {
+            return Ok(Some(frame));
+        }
+        if !src.is_empty() {
+            tracing::warn!(
+                "skipped {} bytes of incomplete length-prefixed data",
+                src.len()
+            );
+        }
+        self.skipping = 0;
+        src.clear();
+        Ok(None)
+    }
+}
+
+/// Decoder splitting process output into gwsocket frames
+///
+/// Each item is a 12 byte header followed by exactly the number of payload bytes given in the header.
+/// On a malformed header, bytes are skipped until a valid header is found.
+#[derive(Debug, Default)]
+pub struct GWSocketCodec {
+    skipped: usize,
+}
+
+impl Decoder for GWSocketCodec {
+    type Item = Bytes;
+    type Error = IOError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Bytes>, IOError> {
+        while src.len() >= HEADER_LEN {
+            let (_, msg_type, length, _) = parse_binary_header(&src[..HEADER_LEN]);
+            if msg_type.is_none() || length > MAX_PAYLOAD_LEN {
+                self.skipped += 1;
+                src.advance(1);
+                continue;
+            }
+            if self.skipped > 0 {
+                tracing::warn!("skipped {} bytes of malformed gwsocket data", self.skipped);
+                self.skipped = 0;
+            }
+
+            let frame_len = HEADER_LEN + length as usize;
+            if src.len() < frame_len {
+                src.reserve(frame_len - src.len());
+                return Ok(None);
+            }
+            return Ok(Some(src.split_to(frame_len).freeze()));
+        }
+        Ok(None)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Bytes>, IOError> {
+        if let Some(frame) = self.decode(src)? {
+            return Ok(Some(frame));
+        }
+        let skipped = self.skipped + src.len();
+        if skipped > 0 {
+            tracing::warn!("skipped {} bytes of incomplete gwsocket data", skipped);
+        }
+        self.skipped = 0;
+        src.clear();
+        Ok(None)
+    }
+}
+
+/// Decoder splitting process output into self-delimiting MessagePack or CBOR values
+///
+/// Each item is exactly one value. On malformed data, bytes are skipped until a value can be decoded.
+/// Values are scanned by their item headers only, resuming where the previous chunk ended.
+#[derive(Debug)]
+pub struct ValueCodec {
+    frame: Frame,
+    skipped: usize,
+    /// Length of the complete items scanned so far
+    scanned: usize,
+    /// Number of items left in each enclosing array or map, or `None` until a break for indefinite length
+    pending: Vec<Option<u64>>,
+}
+
+/// Header of an item in a MessagePack or CBOR value
+enum Item {
+    /// Item of the given length, including its header, and the number of nested items following it
+    Sized(usize, u64),
+    /// Header of the given length, starting an item of indefinite length
+    Indefinite(usize),
+    /// End of an item of indefinite length
+    Break,
+}
+
+/// Maximum nesting of arrays and maps in a value
+const MAX_VALUE_DEPTH: usize = 256;
+
+impl ValueCodec {
+    pub fn new(frame: Frame) -> Self {
+        Self {
+            frame,
+            skipped: 0,
+            scanned: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Continue scanning the value at the start of the data, returning its length once complete
+    fn scan(&mut self, data: &[u8]) -> Result<Option<usize>, ErrorKind> {
+        loop {
+            let rest = &data[self.scanned..];
+            let item = match self.frame {
+                Frame::MsgPack => msgpack_item(rest)?,
+                Frame::CBOR => cbor_item(rest)?,
+                _ => unreachable!("{:?} is not self-delimiting", self.frame),
+            };
+            let Some(item) = item else {
+                return Ok(None);
+            };
+
+            match item {
+                Item::Sized(len, 0) => self.scanned += len,
+                Item::Sized(len, items) => {
+                    self.scanned += len;
+                    self.pending.push(Some(items));
+                }
+                Item::Indefinite(len) => {
+                    self.scanned += len;
+                    self.pending.push(None);
+                }
+                Item::Break => match self.pending.pop() {
+                    Some(None) => self.scanned += 1,
+                    _ => return Err(ErrorKind::InvalidData),
+                },
+            }
+            if self.scanned > MAX_PAYLOAD_LEN as usize || self.pending.len() > MAX_VALUE_DEPTH {
+                return Err(ErrorKind::InvalidData);
+            }
+            if matches!(item, Item::Sized(_, 1..) | Item::Indefinite(_)) {
+                continue;
+            }
+
+            // Count the completed item in its enclosing items, which may complete in turn
+            loop {
+                match self.pending.last_mut() {
+                    None => return Ok(Some(std::mem::take(&mut self.scanned))),
+                    Some(None) => break,
+                    Some(Some(1)) => {
+                        self.pending.pop();
+                    }
+                    Some(Some(items)) => {
+                        *items -= 1;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Forget the partially scanned value
+    fn reset(&mut self) {
+        self.scanned = 0;
+        self.pending.clear();
+    }
+}
+
+/// Read a big-endian unsigned integer of the given byte length following the item type
+fn item_uint(data: &[u8], len: usize) -> Option<u64> {
+    let bytes = data.get(1..1 + len)?;
+    Some(bytes.iter().fold(0, |n, b| n << 8 | u64::from(*b)))
+}
+
+/// Item with a header and a payload of the given length
+fn item_with_payload(header_len: usize, payload_len: u64) -> Result<Item, ErrorKind> {
+    if payload_len > u64::from(MAX_PAYLOAD_LEN) {
+        return Err(ErrorKind::InvalidData);
+    }
+    Ok(Item::Sized(header_len + payload_len as usize, 0))
+}
+
+/// Parse the header of a MessagePack item, or `None` if the data ends before the item
+fn msgpack_item(data: &[u8]) -> Result<Option<Item>, ErrorKind> {
+    let Some(&b) = data.first() else {
+        return Ok(None);
+    };
+    let item = match b {
+        0x00..=0x7f | 0xc0 | 0xc2 | 0xc3 | 0xe0..=0xff => Item::Sized(1, 0),
+        0x80..=0x8f => Item::Sized(1, u64::from(b & 0x0f) * 2),
+        0x90..=0x9f => Item::Sized(1, u64::from(b & 0x0f)),
+        0xa0..=0xbf => item_with_payload(1, u64::from(b & 0x1f))?,
+        0xc1 => return Err(ErrorKind::InvalidData),
+        // bin, ext and str with 1, 2 or 4 byte length, where ext has a type byte
+        0xc4..=0xc9 | 0xd9..=0xdb => {
+            let (k, type_len) = match b {
+                0xc4..=0xc6 => (1 << (b - 0xc4), 0),
+                0xc7..=0xc9 => (1 << (b - 0xc7), 1),
+                _ => (1 << (b - 0xd9), 0),
+            };
+            let Some(len) = item_uint(data, k) else {
+                return Ok(None);
+            };
+            item_with_payload(1 + k + type_len, len)?
+        }
+        0xca => Item::Sized(5, 0),
+        0xcb => Item::Sized(9, 0),
+        // uint and int of 1, 2, 4 or 8 bytes
+        0xcc..=0xcf => Item::Sized(1 + (1 << (b - 0xcc)), 0),
+        0xd0..=0xd3 => Item::Sized(1 + (1 << (b - 0xd0)), 0),
+        // fixext of 1, 2, 4, 8 or 16 bytes, with a type byte
+        0xd4..=0xd8 => Item::Sized(2 + (1 << (b - 0xd4)), 0),
+        // array and map with 2 or 4 byte length
+        0xdc..=0xdf => {
+            let k = 2 << ((b - 0xdc) % 2);
+            let Some(len) = item_uint(data, k) else {
+                return Ok(None);
+            };
+            let items = if b >= 0xde { len * 2 } else { len };
+            if items > u64::from(MAX_PAYLOAD_LEN) {
+                return Err(ErrorKind::InvalidData);
+            }
+            Item::Sized(1 + k, items)
+        }
+    };
+    Ok(match item {
+        Item::Sized(len, 0) if data.len() < len => None,
+        item => Some(item),
+    })
+}
+
+/// Parse the header of a CBOR item, or `None` if the data ends before the item
+fn cbor_item(data: &[u8]) -> Result<Option<Item>, ErrorKind> {
+    let Some(&b) = data.first() else {
+        return Ok(None);
+    };
+    let (major, info) = (b >> 5, b & 0x1f);
+
+    // Argument of the item, following the initial byte
+    let (header_len, arg) = match info {
+        0..=23 => (1, u64::from(info)),
+        24..=27 => {
+            let k = 1 << (info - 24);
+            let Some(arg) = item_uint(data, k) else {
+                return Ok(None);
+            };
+            (1 + k, arg)
+        }
+        31 => {
+            return match major {
+                2..=5 => Ok(Some(Item::Indefinite(1))),
+                7 => Ok(Some(Item::Break)),
+                _ => Err(ErrorKind::InvalidData),
+            };
+        }
+        _ => return Err(ErrorKind::InvalidData),
+    };
+
+    let item = match major {
+        0 | 1 | 7 => Item::Sized(header_len, 0),
+        2 | 3 => item_with_payload(header_len, arg)?,
+        4 | 5 => {
+            let items = if major == 5 {
+                arg.saturating_mul(2)
+            } else {
+                arg
+            };
+            if items > u64::from(MAX_PAYLOAD_LEN) {
+                return Err(ErrorKind::InvalidData);
+            }
+            Item::Sized(header_len, items)
+        }
+        // tag, followed by the tagged item
+        _ => Item::Sized(header_len, 1),
+    };
+    Ok(match item {
+        Item::Sized(len, 0) if data.len() < len => None,
+        item => Some(item),
+    })
+}
+
+impl Decoder for ValueCodec {
+    type Item = Bytes;
+    type Error = IOError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Bytes>, IOError> {
+        while !src.is_empty() {
+            match self.scan(src) {
+                Ok(Some(value_len)) => {
+                    if self.skipped > 0 {
+                        tracing::warn!(
+                            "skipped {} bytes of malformed {:?} data",
+                            self.skipped,
+                            self.frame
+                        );
+                        self.skipped = 0;
+                    }
+                    return Ok(Some(src.split_to(value_len).freeze()));
+                }
+                Ok(None) => return Ok(None),
+                Err(_) => {
+                    self.reset();
+                    self.skipped += 1;
+                    src.advance(1);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Bytes>, IOError> {
+        if let Some(value) = self.decode(src)? {
+            return Ok(Some(value));
+        }
+        let skipped = self.skipped + src.len();
+        if skipped > 0 {
+            tracing::warn!(
+                "skipped {} bytes of incomplete {:?} data",
+                skipped,
+                self.frame
+            );
+        }
+        self.skipped = 0;
+        self.reset();
+        src.clear();
+        Ok(None)
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
-    use super::{Header, Type, parse_binary_header};
+    use std::borrow::Cow;
+
+    use bytes::{Bytes, BytesMut};
+    use tokio_util::codec::Decoder;
+    use warp::ws::Message;
+
+    use super::{
+        GWSocketCodec, Header, LenPrefixCodec, MAX_PAYLOAD_LEN, Type, ValueCodec, binary_header,
+        deserialize, parse_binary_header, parse_json_header, serialize, strip_fields,
+    };
+    use crate::types::{Frame, Recipients};
+
+    fn gwsocket_frame(id: u32, payload: &[u8]) -> Vec<u8> {
+        [
+            &binary_header(id, Type::Text, payload.len() as u32),
+            payload,
+        ]
+        .concat()
+    }
 
     #[test]
     fn test_parse_id() {
         let payload = [
-            (123 as u32).to_le_bytes(),
-            (0 as u32).to_le_bytes(),
-            (0 as u32).to_le_bytes(),
+            123_u32.to_le_bytes(),
+            0_u32.to_le_bytes(),
+            0_u32.to_le_bytes(),
         ]
         .concat();
         let (result, _, _, _) = parse_binary_header(&payload);
@@ -143,9 +885,9 @@ mod tests {
     #[test]
     fn test_parse_id_0_is_broadcast() {
         let payload = [
-            (0 as u32).to_le_bytes(),
-            (0 as u32).to_le_bytes(),
-            (0 as u32).to_le_bytes(),
+            0_u32.to_le_bytes(),
+            0_u32.to_le_bytes(),
+            0_u32.to_le_bytes(),
         ]
         .concat();
         let (result, _, _, _) = parse_binary_header(&payload);
@@ -155,9 +897,9 @@ mod tests {
     #[test]
     fn test_parse_type() {
         let payload = [
-            (0 as u32).to_le_bytes(),
-            (2 as u32).to_le_bytes(),
-            (0 as u32).to_le_bytes(),
+            0_u32.to_le_bytes(),
+            2_u32.to_le_bytes(),
+            0_u32.to_le_bytes(),
         ]
         .concat();
         let (_, result, _, _) = parse_binary_header(&payload);
@@ -167,12 +909,436 @@ mod tests {
     #[test]
     fn test_parse_length() {
         let payload = [
-            (0 as u32).to_le_bytes(),
-            (0 as u32).to_le_bytes(),
-            (123 as u32).to_le_bytes(),
+            0_u32.to_le_bytes(),
+            0_u32.to_le_bytes(),
+            123_u32.to_le_bytes(),
         ]
         .concat();
         let (_, _, result, _) = parse_binary_header(&payload);
         assert_eq!(result, 123);
     }
+
+    #[test]
+    fn test_parse_json_header_ignores_invalid_kick_fields() {
+        let msg = Bytes::from_static(br#"{"_to":2,"_code":"E1","_reason":7,"_binary":"yes"}"#);
+        let (header, _) = parse_json_header(&msg);
+        assert_eq!(header, Header::to(2));
+    }
+
+    #[test]
+    fn test_parse_json_header_keeps_valid_kick_fields() {
+        let msg = Bytes::from_static(br#"{"_kick":[1,2],"_code":4000,"_reason":"cheater"}"#);
+        let (header, _) = parse_json_header(&msg);
+        assert_eq!(header.kick, Some(Recipients::Many(vec![1, 2])));
+        assert_eq!(header.code, Some(4000));
+        assert_eq!(header.reason.as_deref(), Some("cheater"));
+    }
+
+    #[test]
+    fn test_serialize_gwsocket_text() {
+        let result = serialize(Message::text("abc"), 123, Some(Frame::GWSocket)).unwrap();
+        let expected = [
+            &123_u32.to_le_bytes()[..],
+            &1_u32.to_le_bytes(),
+            &3_u32.to_le_bytes(),
+            b"abc",
+        ]
+        .concat();
+        assert!(result.is_binary());
+        assert_eq!(result.as_bytes(), expected);
+    }
+
+    #[test]
+    fn test_serialize_gwsocket_header_bytes() {
+        let result = serialize(Message::text("abc"), 0x01020304, Some(Frame::GWSocket)).unwrap();
+        assert_eq!(
+            result.as_bytes(),
+            [4, 3, 2, 1, 1, 0, 0, 0, 3, 0, 0, 0, b'a', b'b', b'c']
+        );
+    }
+
+    #[test]
+    fn test_serialize_gwsocket_binary() {
+        let result = serialize(Message::binary([0, 1]), 1, Some(Frame::GWSocket)).unwrap();
+        let (header, msg_type, length, payload) = parse_binary_header(result.as_bytes());
+        assert_eq!(header, Header::to(1));
+        assert_eq!(msg_type, Some(Type::Binary));
+        assert_eq!(length, 2);
+        assert_eq!(payload, [0, 1]);
+    }
+
+    #[test]
+    fn test_decode_gwsocket_payload_with_delimiter() {
+        let frame = gwsocket_frame(1, b"a\nb");
+        let mut src = BytesMut::from(&frame[..]);
+        let result = GWSocketCodec::default().decode(&mut src).unwrap();
+        assert_eq!(result.as_deref(), Some(&frame[..]));
+    }
+
+    #[test]
+    fn test_decode_gwsocket_chunked() {
+        let frame = gwsocket_frame(1, b"abc");
+        let mut codec = GWSocketCodec::default();
+        let mut src = BytesMut::from(&frame[..14]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+
+        src.extend_from_slice(&frame[14..]);
+        let result = codec.decode(&mut src).unwrap();
+        assert_eq!(result.as_deref(), Some(&frame[..]));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn test_decode_gwsocket_resyncs_after_garbage() {
+        let frame = gwsocket_frame(2, b"abc");
+        let mut src = BytesMut::from(&[b"xy\n", &frame[..]].concat()[..]);
+        let result = GWSocketCodec::default().decode(&mut src).unwrap();
+        assert_eq!(result.as_deref(), Some(&frame[..]));
+    }
+
+    #[test]
+    fn test_decode_gwsocket_eof_drops_incomplete() {
+        let frame = gwsocket_frame(1, b"abc");
+        let mut src = BytesMut::from(&frame[..13]);
+        let result = GWSocketCodec::default().decode_eof(&mut src).unwrap();
+        assert_eq!(result, None);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn test_serialize_lenprefix() {
+        let result = serialize(Message::binary([0, 10]), 123, Some(Frame::LenPrefix)).unwrap();
+        let expected = [&2_u32.to_be_bytes()[..], &123_u32.to_be_bytes(), &[0, 10]].concat();
+        assert!(result.is_binary());
+        assert_eq!(result.as_bytes(), expected);
+    }
+
+    #[test]
+    fn test_deserialize_lenprefix() {
+        let payload = [&2_u32.to_be_bytes()[..], &123_u32.to_be_bytes(), &[0, 10]].concat();
+        let payload = bytes::Bytes::from(payload);
+        let result = deserialize(&payload, Some(Frame::LenPrefix));
+        assert_eq!(result, Ok((Header::to(123), Cow::Borrowed(&[0, 10][..]))));
+    }
+
+    #[test]
+    fn test_decode_lenprefix_chunked() {
+        let frame = [&3_u32.to_be_bytes()[..], &0_u32.to_be_bytes(), b"a\nc"].concat();
+        let mut codec = LenPrefixCodec::new(Frame::LenPrefix);
+        let mut src = BytesMut::from(&frame[..9]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+
+        src.extend_from_slice(&frame[9..]);
+        let result = codec.decode(&mut src).unwrap();
+        assert_eq!(result.as_deref(), Some(&frame[..]));
+    }
+
+    #[test]
+    fn test_decode_lenprefix_skips_oversized_frame() {
+        let length = MAX_PAYLOAD_LEN + 1;
+        let frame = [&3_u32.to_be_bytes()[..], &0_u32.to_be_bytes(), b"abc"].concat();
+        let mut codec = LenPrefixCodec::new(Frame::LenPrefix);
+        let mut src =
+            BytesMut::from(&[&length.to_be_bytes()[..], &0_u32.to_be_bytes()].concat()[..]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+
+        let chunk = vec![0; 1 << 20];
+        for _ in 0..64 {
+            src.extend_from_slice(&chunk);
+            assert_eq!(codec.decode(&mut src).unwrap(), None);
+        }
+        src.extend_from_slice(&[0]);
+        src.extend_from_slice(&frame);
+        let result = codec.decode(&mut src).unwrap();
+        assert_eq!(result.as_deref(), Some(&frame[..]));
+    }
+
+    #[test]
+    fn test_serialize_length() {
+        let result = serialize(Message::binary([0, 10]), 123, Some(Frame::Length)).unwrap();
+        let expected = [&2_u32.to_be_bytes()[..], &[0, 10]].concat();
+        assert!(result.is_binary());
+        assert_eq!(result.as_bytes(), expected);
+    }
+
+    #[test]
+    fn test_deserialize_length() {
+        let payload = bytes::Bytes::from([&2_u32.to_be_bytes()[..], &[0, 10]].concat());
+        let result = deserialize(&payload, Some(Frame::Length));
+        assert_eq!(
+            result,
+            Ok((Header::broadcast(), Cow::Borrowed(&[0, 10][..])))
+        );
+    }
+
+    #[test]
+    fn test_serialize_msgpack_injects_from() {
+        let msg = rmp_serde::to_vec_named(&serde_json::json!({"_from": 9, "foo": "bar"})).unwrap();
+        let result = serialize(Message::binary(msg), 123, Some(Frame::MsgPack)).unwrap();
+        let value: serde_json::Value = rmp_serde::from_slice(result.as_bytes()).unwrap();
+        assert!(result.is_binary());
+        assert_eq!(value, serde_json::json!({"_from": 123, "foo": "bar"}));
+    }
+
+    #[test]
+    fn test_serialize_msgpack_rejects_non_map() {
+        let msg = rmp_serde::to_vec(&[1, 2]).unwrap();
+        let result = serialize(Message::binary(msg), 123, Some(Frame::MsgPack));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_deserialize_msgpack_header() {
+        let msg = rmp_serde::to_vec_named(&serde_json::json!({"_to": 2, "_cache": true})).unwrap();
+        let msg = bytes::Bytes::from(msg);
+        let (result, _) = deserialize(&msg, Some(Frame::MsgPack)).unwrap();
+        assert_eq!(result.to, Some(Recipients::One(2)));
+        assert!(result.is_cache);
+    }
+
+    #[test]
+    fn test_decode_msgpack_chunked() {
+        let value = rmp_serde::to_vec_named(&serde_json::json!({"foo": "a\nb"})).unwrap();
+        let mut codec = ValueCodec::new(Frame::MsgPack);
+        let mut src = BytesMut::from(&value[..3]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+
+        src.extend_from_slice(&value[3..]);
+        src.extend_from_slice(&value);
+        assert_eq!(codec.decode(&mut src).unwrap().as_deref(), Some(&value[..]));
+        assert_eq!(codec.decode(&mut src).unwrap().as_deref(), Some(&value[..]));
+        assert!(src.is_empty());
+    }
+
+    fn to_cbor(value: serde_json::Value) -> Vec<u8> {
+        let mut data = Vec::new();
+        ciborium::ser::into_writer(&value, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_serialize_cbor_injects_from() {
+        let msg = to_cbor(serde_json::json!({"_from": 9, "foo": "bar"}));
+        let result = serialize(Message::binary(msg), 123, Some(Frame::CBOR)).unwrap();
+        let value: serde_json::Value = ciborium::de::from_reader(result.as_bytes()).unwrap();
+        assert!(result.is_binary());
+        assert_eq!(value, serde_json::json!({"_from": 123, "foo": "bar"}));
+    }
+
+    #[test]
+    fn test_deserialize_cbor_header() {
+        let msg = to_cbor(serde_json::json!({"_to": 2, "_meta": true}));
+        let msg = bytes::Bytes::from(msg);
+        let (result, _) = deserialize(&msg, Some(Frame::CBOR)).unwrap();
+        assert_eq!(result.to, Some(Recipients::One(2)));
+        assert!(result.is_meta);
+    }
+
+    #[test]
+    fn test_decode_msgpack_nested_byte_by_byte() {
+        let value = rmp_serde::to_vec_named(&serde_json::json!({
+            "a": [1, -200, 3.5, null, true, "x".repeat(300)],
+            "b": {"c": [[], {}], "d": 70000},
+        }))
+        .unwrap();
+        let mut codec = ValueCodec::new(Frame::MsgPack);
+        let mut src = BytesMut::new();
+        for byte in &value[..value.len() - 1] {
+            src.extend_from_slice(&[*byte]);
+            assert_eq!(codec.decode(&mut src).unwrap(), None);
+        }
+
+        src.extend_from_slice(&value[value.len() - 1..]);
+        assert_eq!(codec.decode(&mut src).unwrap().as_deref(), Some(&value[..]));
+    }
+
+    #[test]
+    fn test_decode_msgpack_skips_oversized_length() {
+        let value = rmp_serde::to_vec_named(&serde_json::json!({"foo": 1})).unwrap();
+        let mut codec = ValueCodec::new(Frame::MsgPack);
+        let mut src = BytesMut::from(&[0xc6, 0xc1, 0xc1, 0xc1, 0xc1][..]);
+        src.extend_from_slice(&value);
+        assert_eq!(codec.decode(&mut src).unwrap().as_deref(), Some(&value[..]));
+    }
+
+    #[test]
+    fn test_decode_cbor_indefinite_length() {
+        // {"a": [1, 2]} with indefinite map and array
+        let value = [0xbf, 0x61, b'a', 0x9f, 0x01, 0x02, 0xff, 0xff];
+        let mut codec = ValueCodec::new(Frame::CBOR);
+        let mut src = BytesMut::from(&value[..7]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+
+        src.extend_from_slice(&value[7..]);
+        assert_eq!(codec.decode(&mut src).unwrap().as_deref(), Some(&value[..]));
+    }
+
+    #[test]
+    fn test_decode_cbor_resyncs_after_garbage() {
+        let value = to_cbor(serde_json::json!({"foo": "a\nb"}));
+        let mut codec = ValueCodec::new(Frame::CBOR);
+        let mut src = BytesMut::from(&value[..3]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+
+        src.extend_from_slice(&value[3..]);
+        src.extend_from_slice(&[0xff]);
+        src.extend_from_slice(&value);
+        assert_eq!(codec.decode(&mut src).unwrap().as_deref(), Some(&value[..]));
+        assert_eq!(codec.decode(&mut src).unwrap().as_deref(), Some(&value[..]));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn test_parse_json_header_to_list() {
+        let msg = bytes::Bytes::from(r#"{"_to": [1, 4, 7]}"#);
+        let (result, _) = deserialize(&msg, Some(Frame::JSON)).unwrap();
+        assert_eq!(result.to, Some(Recipients::Many(vec![1, 4, 7])));
+        assert!(result.to.unwrap().contains(4));
+    }
+
+    #[test]
+    fn test_parse_json_header_except() {
+        let msg = bytes::Bytes::from(r#"{"_except": 3}"#);
+        let (result, _) = deserialize(&msg, Some(Frame::JSON)).unwrap();
+        assert_eq!(result.to, None);
+        assert_eq!(result.except, Some(Recipients::One(3)));
+    }
+
+    #[test]
+    fn test_serialize_envelope_text() {
+        let result = serialize(Message::text("hello"), 123, Some(Frame::Envelope)).unwrap();
+        let expected: serde_json::Value = serde_json::from_str(result.to_str().unwrap()).unwrap();
+        assert_eq!(expected, serde_json::json!({"_from": 123, "data": "hello"}));
+    }
+
+    #[test]
+    fn test_serialize_envelope_binary() {
+        let result = serialize(Message::binary([0, 1]), 123, Some(Frame::Envelope)).unwrap();
+        let expected: serde_json::Value = serde_json::from_str(result.to_str().unwrap()).unwrap();
+        assert_eq!(
+            expected,
+            serde_json::json!({"_from": 123, "_binary": true, "data": "AAE="})
+        );
+    }
+
+    #[test]
+    fn test_deserialize_envelope() {
+        let msg = bytes::Bytes::from(r#"{"_to": 3, "data": "hello"}"#);
+        let (result, data) = deserialize(&msg, Some(Frame::Envelope)).unwrap();
+        assert_eq!(result.to, Some(Recipients::One(3)));
+        assert_eq!(&data[..], b"hello");
+    }
+
+    #[test]
+    fn test_deserialize_envelope_binary() {
+        let msg = bytes::Bytes::from(r#"{"_binary": true, "data": "AAE="}"#);
+        let (result, data) = deserialize(&msg, Some(Frame::Envelope)).unwrap();
+        assert!(result.is_binary);
+        assert_eq!(&data[..], [0, 1]);
+    }
+
+    #[test]
+    fn test_serialize_jsonrpc_namespaces_id() {
+        let msg = Message::text(r#"{"jsonrpc":"2.0","id":1,"method":"add"}"#);
+        let result = serialize(msg, 123, Some(Frame::JSONRPC)).unwrap();
+        let expected: serde_json::Value = serde_json::from_str(result.to_str().unwrap()).unwrap();
+        assert_eq!(
+            expected,
+            serde_json::json!({"jsonrpc": "2.0", "id": "123:1", "method": "add"})
+        );
+    }
+
+    #[test]
+    fn test_serialize_jsonrpc_notification() {
+        let msg = Message::text(r#"{"jsonrpc":"2.0","method":"ping"}"#);
+        let result = serialize(msg, 123, Some(Frame::JSONRPC)).unwrap();
+        assert_eq!(result.to_str(), Ok(r#"{"jsonrpc":"2.0","method":"ping"}"#));
+    }
+
+    #[test]
+    fn test_deserialize_jsonrpc_response() {
+        let msg = bytes::Bytes::from(r#"{"jsonrpc":"2.0","id":"123:\"a\"","result":3}"#);
+        let (result, data) = deserialize(&msg, Some(Frame::JSONRPC)).unwrap();
+        assert_eq!(result.to, Some(Recipients::One(123)));
+        assert_eq!(&data[..], br#"{"id":"a","jsonrpc":"2.0","result":3}"#);
+    }
+
+    #[test]
+    fn test_deserialize_jsonrpc_response_without_id() {
+        let msg = bytes::Bytes::from(r#"{"jsonrpc":"2.0","id":null,"error":{"code":-32700}}"#);
+        assert!(deserialize(&msg, Some(Frame::JSONRPC)).is_err());
+    }
+
+    #[test]
+    fn test_serialize_jsonrpc_batch() {
+        let msg = Message::text(
+            r#"[{"jsonrpc":"2.0","id":1,"method":"a"},{"jsonrpc":"2.0","method":"b"}]"#,
+        );
+        let result = serialize(msg, 123, Some(Frame::JSONRPC)).unwrap();
+        let expected: serde_json::Value = serde_json::from_str(result.to_str().unwrap()).unwrap();
+        assert_eq!(
+            expected,
+            serde_json::json!([
+                {"jsonrpc": "2.0", "id": "123:1", "method": "a"},
+                {"jsonrpc": "2.0", "method": "b"}
+            ])
+        );
+    }
+
+    #[test]
+    fn test_deserialize_jsonrpc_batch_response() {
+        let msg = bytes::Bytes::from(
+            r#"[{"id":"123:1","result":3},{"id":null,"error":{"code":-32600}}]"#,
+        );
+        let (result, data) = deserialize(&msg, Some(Frame::JSONRPC)).unwrap();
+        assert_eq!(result.to, Some(Recipients::One(123)));
+        assert_eq!(
+            &data[..],
+            br#"[{"id":1,"result":3},{"error":{"code":-32600},"id":null}]"#
+        );
+    }
+
+    #[test]
+    fn test_deserialize_jsonrpc_batch_response_of_several_clients() {
+        let msg = bytes::Bytes::from(r#"[{"id":"1:1","result":3},{"id":"2:1","result":4}]"#);
+        assert!(deserialize(&msg, Some(Frame::JSONRPC)).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_jsonrpc_notification() {
+        let msg = bytes::Bytes::from(r#"{"jsonrpc":"2.0","method":"tick"}"#);
+        let (result, data) = deserialize(&msg, Some(Frame::JSONRPC)).unwrap();
+        assert_eq!(result, Header::broadcast());
+        assert_eq!(data, Cow::Borrowed(&msg[..]));
+    }
+
+    #[test]
+    fn test_strip_fields_msgpack() {
+        let msg = rmp_serde::to_vec_named(&serde_json::json!({"_to": 1, "foo": 2})).unwrap();
+        let fields = vec!["_to".to_string()];
+        let result = strip_fields(Cow::Borrowed(&msg), Some(Frame::MsgPack), &fields);
+        let expected = rmp_serde::to_vec_named(&serde_json::json!({"foo": 2})).unwrap();
+        assert_eq!(result, Cow::<[u8]>::Owned(expected));
+    }
+
+    #[test]
+    fn test_strip_fields_keeps_message_without_fields() {
+        let fields = vec!["_to".to_string()];
+        let msg = br#"{"b": 1, "a": 2}"#;
+        let result = strip_fields(Cow::Borrowed(msg), Some(Frame::JSON), &fields);
+        assert_eq!(result, Cow::Borrowed(msg));
+    }
+
+    #[test]
+    fn test_strip_fields_ignores_non_object() {
+        let fields = vec!["_to".to_string()];
+        let result = strip_fields(Cow::Borrowed(b"[1]"), Some(Frame::JSON), &fields);
+        assert_eq!(result, Cow::Borrowed(b"[1]"));
+    }
+
+    #[test]
+    fn test_deserialize_envelope_without_data() {
+        let msg = bytes::Bytes::from(r#"{"_to": 3}"#);
+        assert!(deserialize(&msg, Some(Frame::Envelope)).is_err());
+    }
 }
@@ -7,18 +7,28 @@ use {
     std::sync::{Arc, RwLock},
 };
 
-use crate::types::RoomID;
+use crate::types::{ExitReason, RoomID, SharedGroups};
 
 #[derive(Clone, Hash, PartialEq, Eq, EncodeLabelSet, Debug)]
 pub struct Labels {
     room: RoomID,
 }
 
+#[derive(Clone, Hash, PartialEq, Eq, EncodeLabelSet, Debug)]
+pub struct ExitLabels {
+    reason: String,
+}
+
 #[derive(Clone)]
 pub struct Metrics {
     metas: Arc<RwLock<HashMap<String, Value>>>,
+    groups: Arc<RwLock<HashMap<String, SharedGroups>>>,
+    idle: Arc<RwLock<HashSet<String>>>,
     ws_connections_counter: Family<Labels, Counter>,
     ws_connections_open_gauge: Family<Labels, Gauge>,
+    ws_lagged_counter: Family<Labels, Counter>,
+    process_exits_counter: Family<ExitLabels, Counter>,
+    process_pool_gauge: Gauge,
     // prometheus_client does not expose iterators over `Metrics` or `Labels`
     // https://github.com/prometheus/client_rust/issues/131
     ws_connections_labels: Option<Arc<RwLock<HashSet<String>>>>,
@@ -28,6 +38,9 @@ impl Metrics {
     pub fn new(registry: &mut Option<Registry>, track_labels: bool) -> Self {
         let ws_connections_counter = Family::<Labels, Counter>::default();
         let ws_connections_open_gauge = Family::<Labels, Gauge>::default();
+        let ws_lagged_counter = Family::<Labels, Counter>::default();
+        let process_exits_counter = Family::<ExitLabels, Counter>::default();
+        let process_pool_gauge = Gauge::default();
         let ws_connections_labels =
             track_labels.then(|| Arc::new(RwLock::new(HashSet::with_capacity(100))));
 
@@ -42,12 +55,32 @@ impl Metrics {
                 "Number of open websocket connections",
                 ws_connections_open_gauge.clone(),
             );
+            registry.register(
+                "scalesocket_websocket_lagged",
+                "Number of times websocket clients fell behind the process output",
+                ws_lagged_counter.clone(),
+            );
+            registry.register(
+                "scalesocket_process_exits",
+                "Number of child process exits by reason",
+                process_exits_counter.clone(),
+            );
+            registry.register(
+                "scalesocket_process_pool",
+                "Number of pooled processes waiting for a room",
+                process_pool_gauge.clone(),
+            );
         }
 
         Self {
             metas: Arc::new(RwLock::new(HashMap::new())),
+            groups: Arc::new(RwLock::new(HashMap::new())),
+            idle: Arc::new(RwLock::new(HashSet::new())),
             ws_connections_counter,
             ws_connections_open_gauge,
+            ws_lagged_counter,
+            process_exits_counter,
+            process_pool_gauge,
             ws_connections_labels,
         }
     }
@@ -91,6 +124,22 @@ impl Metrics {
         }
     }
 
+    pub fn inc_ws_lagged(&self, room: &str) {
+        self.ws_lagged_counter
+            .get_or_create(&Labels {
+                room: room.to_string(),
+            })
+            .inc();
+    }
+
+    pub fn inc_process_exits(&self, reason: ExitReason) {
+        self.process_exits_counter
+            .get_or_create(&ExitLabels {
+                reason: reason.as_str().to_string(),
+            })
+            .inc();
+    }
+
     pub fn set_metadata(&self, room: &str, mut metadata: Value) {
         if let Some(obj) = metadata.as_object_mut() {
             obj.remove("_meta");
@@ -102,7 +151,30 @@ impl Metrics {
         }
     }
 
+    pub fn set_groups(&self, room: &str, groups: SharedGroups) {
+        self.groups
+            .write()
+            .expect("poisoned lock")
+            .insert(room.to_owned(), groups);
+    }
+
+    pub fn set_pool_size(&self, size: usize) {
+        self.process_pool_gauge.set(size as i64);
+    }
+
+    pub fn set_idle(&self, room: &str, is_idle: bool) {
+        let mut idle = self.idle.write().expect("poisoned lock");
+        if is_idle {
+            idle.insert(room.to_owned());
+        } else {
+            idle.remove(room);
+        }
+    }
+
     pub fn clear(&self, room: &str) {
+        self.idle.write().expect("poisoned lock").remove(room);
+        self.groups.write().expect("poisoned lock").remove(room);
+
         self.ws_connections_open_gauge.remove(&Labels {
             room: room.to_owned(),
         });
@@ -128,7 +200,9 @@ impl Metrics {
         json!({
            "name": room.clone(),
            "connections": self.get_room_connections(room.clone()),
-           "metadata": self.get_room_metadata(&room)
+           "state": self.get_room_state(&room),
+           "metadata": self.get_room_metadata(&room),
+           "groups": self.get_room_groups(&room)
         })
     }
 
@@ -138,7 +212,20 @@ impl Metrics {
             .get()
     }
 
+    pub fn get_room_state(&self, room: &str) -> &'static str {
+        match self.idle.read().expect("poisoned lock").contains(room) {
+            true => "idle",
+            false => "active",
+        }
+    }
+
     pub fn get_room_metadata(&self, room: &str) -> Option<Value> {
         self.metas.read().expect("poisoned lock").get(room).cloned()
     }
+
+    pub fn get_room_groups(&self, room: &str) -> Option<Value> {
+        let groups = self.groups.read().expect("poisoned lock");
+        let groups = groups.get(room)?.read().expect("poisoned lock");
+        serde_json::to_value(&*groups).ok()
+    }
 }
@@ -1,71 +1,238 @@
 use {
     bytes::Bytes,
     futures::TryStreamExt,
-    futures::{FutureExt, StreamExt},
+    futures::{FutureExt, SinkExt, StreamExt, future},
     std::io::{Error as IOError, Result as IOResult},
     std::sync::Arc,
-    tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    tokio::net::TcpStream,
+    tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader},
+    tokio::net::{TcpStream, UnixStream},
     tokio::process::Child,
     tokio::sync::Barrier,
-    tokio::time::{Duration, sleep},
-    tokio_stream::wrappers::{LinesStream, UnboundedReceiverStream},
+    tokio::time::{Duration, Instant, sleep, timeout},
+    tokio_stream::wrappers::{LinesStream, ReceiverStream, SplitStream},
+    tokio_tungstenite::{
+        MaybeTlsStream, WebSocketStream, connect_async, tungstenite::Message as WsMessage,
+    },
     tokio_util::codec::{AnyDelimiterCodec, BytesCodec, FramedRead},
-    tracing::instrument,
+    tracing::{Instrument, instrument},
     warp::ws::Message,
 };
 
 use crate::{
     channel::{Channel, Source},
+    control::{self, Control},
     error::{AppError, AppResult},
-    types::{FromProcessRxAny, FromProcessTxAny, ShutdownRxStream, ToProcessRxStream},
-    utils::exit_code,
+    message::{GWSocketCodec, LenPrefixCodec, ValueCodec},
+    types::{
+        ExitReason, Frame, FromProcessErrAny, FromProcessFrameAny, FromProcessRxAny,
+        FromProcessTxAny, ShutdownRxStream, StopSignal, ToProcessFrameAny, ToProcessRxStream,
+    },
+    utils::{exit_code, exit_signal, send_signal},
 };
 
 #[instrument(parent = None, name = "process", skip_all)]
-pub async fn handle(mut channel: Channel, barrier: Option<Arc<Barrier>>) -> AppResult<Option<i32>> {
+pub async fn handle(
+    mut channel: Channel,
+    barrier: Option<Arc<Barrier>>,
+) -> AppResult<(Option<i32>, ExitReason)> {
+    let exit = run(&mut channel, barrier).await;
+
+    // Inform clients, also when the process failed
+    let drain_deadline = Instant::now() + LAG_DRAIN_TIMEOUT;
+    channel
+        .close_sock(exit.as_ref().ok().copied(), drain_deadline)
+        .await;
+
+    if let Ok(exit) = exit {
+        channel.notify_exit(exit).await;
+    }
+
+    tracing::debug!("process handler done");
+    exit
+}
+
+async fn run(
+    channel: &mut Channel,
+    barrier: Option<Arc<Barrier>>,
+) -> AppResult<(Option<i32>, ExitReason)> {
     if let Some(barrier) = barrier {
         barrier.wait().await;
         tracing::debug!("waited for connection");
     }
-    let mut proc = spawn(&mut channel).await?;
-    let mut child = proc.child.take().unwrap();
+    let mut sock_rx: ToProcessRxStream = ReceiverStream::new(channel.rx.take().unwrap());
+    let mut kill_rx: ShutdownRxStream = channel.kill_rx.take().unwrap().into_stream();
+    let mut lifecycle_rx = channel.lifecycle_rx.take().unwrap();
+    let drained = channel.drained.clone();
+    let mut restarts = 0;
+
+    // Control channel outlives restarts of the child
+    let mut control_rx: FromProcessErrAny = match channel.open_control()? {
+        Some(Control { rx, tx }) => {
+            let events = channel.control_rx.take().unwrap();
+            tokio::spawn(control::write_events(tx, events).in_current_span());
+            rx
+        }
+        None => Box::new(futures::stream::empty()),
+    };
 
-    tracing::debug!("listening to child");
+    loop {
+        let mut proc = spawn(channel).await?;
+        let mut child = proc.child.take().unwrap();
 
-    let exit_code = loop {
-        tokio::select! {
-            Some(v) = proc.sock_rx.next() => {
-                proc.write_child(v, channel.is_binary).await?;
+        // Pooled child waits for a room before listening
+        if let Some(bind_rx) = channel.bind_rx.take() {
+            tracing::debug!("waiting for room");
+
+            tokio::select! {
+                Ok((room, bind_msg, cache, groups, barrier)) = bind_rx => {
+                    tracing::debug!(room, "bound to room");
+                    channel.room = room;
+                    channel.bind_msg = bind_msg;
+                    channel.cache = cache;
+                    channel.groups = groups;
+                    barrier.wait().await;
+                }
+                _ = kill_rx.next() => {
+                    let stop_timeout = Duration::from_secs(channel.stop_timeout);
+                    return Ok(stop(&mut child, channel.stop_signal, stop_timeout).await);
+                }
+                status = child.wait() => {
+                    let signal = exit_signal(&status);
+                    tracing::debug!(signal, "pooled child exited");
+                    return Ok((exit_code(status), channel.exit_reason(signal)));
+                }
             }
-            Some(Ok(msg)) = proc.proc_rx.next() => {
-                channel.write_sock(msg);
-            },
-            _ = proc.kill_rx.next() => {
-                // TODO send SIGINT and wait
-                let _ = child.kill().await;
-                break None;
+        }
+
+        // Inform pooled child of its room, also when restarted
+        if let Some(ref bind_msg) = channel.bind_msg {
+            let msg = Message::text(bind_msg.clone());
+            proc.write_child(msg, channel.is_binary_in()).await?;
+        }
+
+        tracing::debug!("listening to child");
+
+        let exit = loop {
+            tokio::select! {
+                Some(msg) = lifecycle_rx.recv() => {
+                    proc.write_child(msg, channel.is_binary_in()).await?;
+                }
+                Some(v) = sock_rx.next() => {
+                    // Join messages first, so that the child knows the client before its messages
+                    while let Ok(msg) = lifecycle_rx.try_recv() {
+                        proc.write_child(msg, channel.is_binary_in()).await?;
+                    }
+                    proc.write_child(v, channel.is_binary_in()).await?;
+                }
+                Some(Ok(msg)) = proc.proc_rx.next(), if channel.can_write_sock() => {
+                    channel.write_sock(msg).await;
+                },
+                Some(Ok(msg)) = proc.frame_rx.next(), if channel.can_write_sock() => {
+                    channel.write_sock_frame(msg).await;
+                },
+                // Recheck lagging clients while output is blocked
+                _ = drained.notified(), if !channel.can_write_sock() => {},
+                Some(Ok(line)) = proc.err_rx.next(), if channel.can_write_sock() => {
+                    channel.write_stderr(line).await;
+                },
+                Some(Ok(line)) = control_rx.next(), if channel.can_write_sock() => {
+                    channel.write_control(line).await;
+                },
+                _ = kill_rx.next() => {
+                    let stop_timeout = Duration::from_secs(channel.stop_timeout);
+                    break stop(&mut child, channel.stop_signal, stop_timeout).await;
+                }
+                status = child.wait() => {
+                    let signal = exit_signal(&status);
+                    tracing::debug!(signal, "child exited");
+                    break (exit_code(status), channel.exit_reason(signal));
+                },
             }
-            status = child.wait() => {
-                break exit_code(status);
-            },
+        };
+
+        // Joins not yet read by the exited child are replayed to a restarted child instead
+        {
+            let mut exits = channel.exits.lock().expect("poisoned lock");
+            *exits += 1;
+            while lifecycle_rx.try_recv().is_ok() {}
+        }
+
+        // Stream remaining messages, waiting a limited time for lagging clients
+        let drain_deadline = Instant::now() + LAG_DRAIN_TIMEOUT;
+        while let Some(Ok(msg)) = proc.proc_rx.next().await {
+            channel.wait_for_clients(drain_deadline).await;
+            channel.write_sock(msg).await;
+        }
+        while let Some(Ok(msg)) = proc.frame_rx.next().await {
+            channel.wait_for_clients(drain_deadline).await;
+            channel.write_sock_frame(msg).await;
+        }
+        while let Some(Ok(line)) = proc.err_rx.next().await {
+            channel.wait_for_clients(drain_deadline).await;
+            channel.write_stderr(line).await;
+        }
+        // Control channel stays open, so only run commands already written
+        while let Some(Some(Ok(line))) = control_rx.next().now_or_never() {
+            channel.wait_for_clients(drain_deadline).await;
+            channel.write_control(line).await;
+        }
+
+        if !channel.should_restart(exit, restarts) {
+            return Ok(exit);
         }
-    };
 
-    // Stream remaining messages
-    while let Some(Ok(msg)) = proc.proc_rx.next().await {
-        channel.write_sock(msg);
+        let backoff = restart_backoff(channel.restart_delay, restarts);
+        restarts += 1;
+        tracing::debug!("restarting child in {:?}", backoff);
+        channel.notify_restart(exit, restarts).await;
+
+        tokio::select! {
+            _ = sleep(backoff) => {}
+            _ = kill_rx.next() => return Ok(exit),
+        }
     }
+}
 
-    tracing::debug!("process handler done");
-    Ok(exit_code)
+const LAG_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Exponential backoff before restarting the child, capped to `MAX_RESTART_BACKOFF`
+fn restart_backoff(delay: u64, restarts: u32) -> Duration {
+    Duration::from_secs(delay)
+        .saturating_mul(2_u32.saturating_pow(restarts))
+        .min(MAX_RESTART_BACKOFF)
+}
+
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Stop child with the given signal, and kill it if it does not exit in time
+async fn stop(
+    child: &mut Child,
+    signal: StopSignal,
+    stop_timeout: Duration,
+) -> (Option<i32>, ExitReason) {
+    tracing::debug!("sending {:?} to child", signal);
+
+    if let Err(e) = send_signal(child, signal) {
+        tracing::debug!("failed to signal child: {}", e);
+    }
+
+    match timeout(stop_timeout, child.wait()).await {
+        Ok(status) => {
+            tracing::debug!(signal = exit_signal(&status), "child stopped");
+            (exit_code(status), ExitReason::Stopped)
+        }
+        Err(_) => {
+            tracing::debug!("child did not stop in time, sending SIGKILL");
+            let _ = child.kill().await;
+            (None, ExitReason::Killed)
+        }
+    }
 }
 
 async fn spawn(channel: &mut Channel) -> AppResult<RunningProcess> {
-    let kill_rx = channel.kill_rx.take().unwrap().into_stream();
-    let sock_rx = UnboundedReceiverStream::new(channel.rx.take().unwrap());
+    channel.create_cgroup()?;
 
-    match channel.source.take().unwrap() {
+    match channel.source.as_mut().unwrap() {
         Source::Stdio(cmd) => {
             let mut child = cmd.spawn()?;
 
@@ -88,103 +255,295 @@ async fn spawn(channel: &mut Channel) -> AppResult<RunningProcess> {
                 .stdout
                 .take()
                 .ok_or(AppError::ProcessStdIOError("stdout"))?;
-
-            let proc_rx: Box<dyn futures::Stream<Item = IOResult<Bytes>> + Unpin + Send> =
-                match channel.delimiters.as_str() {
-                    "" => {
-                        let buffer = BufReader::new(stdout);
-                        let stream = FramedRead::new(buffer, BytesCodec::new());
-                        Box::new(stream.map_ok(Bytes::from))
-                    }
-                    "\n" => {
-                        let buffer = BufReader::new(stdout);
-                        let stream = LinesStream::new(buffer.lines());
-                        Box::new(stream.map_ok(Bytes::from))
-                    }
-                    _ => {
-                        let buffer = BufReader::new(stdout);
-                        let delimiters: Vec<u8> = channel.delimiters.clone().into_bytes();
-                        let stream = FramedRead::new(
-                            buffer,
-                            AnyDelimiterCodec::new(delimiters.clone(), delimiters),
-                        );
-                        Box::new(stream.map_ok(Bytes::from).map_err(IOError::other))
-                    }
-                };
+            let err_rx = stderr_lines(&mut child);
 
             Ok(RunningProcess {
                 child: Some(child),
-                sock_rx,
-                proc_rx,
+                proc_rx: child_rx(stdout, channel),
                 proc_tx: Box::new(stdin),
-                kill_rx,
+                err_rx,
+                frame_rx: Box::new(futures::stream::empty()),
+                frame_tx: None,
             })
         }
         Source::Tcp(cmd, addr) => {
-            let child = cmd.spawn()?;
-
-            if let Some(attach_delay) = channel.attach_delay {
-                tracing::debug!("delaying tcp connect for {} seconds", attach_delay);
-                sleep(Duration::from_secs(attach_delay)).await;
-            }
+            let addr = *addr;
+            let mut child = cmd.spawn()?;
+            let err_rx = stderr_lines(&mut child);
 
-            let stream = match TcpStream::connect(addr).await {
-                Ok(s) => s,
-                Err(e) => {
-                    return Err(AppError::NetworkError(
-                        addr.to_string(),
-                        e.kind().to_string(),
-                    ));
-                }
-            };
+            let stream = attach_socket(channel, &mut child, addr.to_string(), || {
+                TcpStream::connect(addr)
+            })
+            .await?;
+            let (rx, tx) = stream.into_split();
 
-            tracing::debug!("connected to childprocess at {}", addr);
+            Ok(RunningProcess {
+                child: Some(child),
+                proc_tx: Box::new(tx),
+                proc_rx: child_rx(rx, channel),
+                err_rx,
+                frame_rx: Box::new(futures::stream::empty()),
+                frame_tx: None,
+            })
+        }
+        Source::Unix(cmd, path) => {
+            let path = path.clone();
+            // Remove socket left behind by a previous child
+            let _ = std::fs::remove_file(&path);
+            let mut child = cmd.spawn()?;
+            let err_rx = stderr_lines(&mut child);
 
+            let stream = attach_socket(channel, &mut child, path.display().to_string(), || {
+                UnixStream::connect(&path)
+            })
+            .await?;
             let (rx, tx) = stream.into_split();
-            let proc_rx: Box<dyn futures::Stream<Item = IOResult<Bytes>> + Unpin + Send> =
-                match channel.delimiters.as_str() {
-                    "" => {
-                        let buffer = BufReader::new(rx);
-                        let stream = FramedRead::new(buffer, BytesCodec::new());
-                        Box::new(stream.map_ok(Bytes::from))
-                    }
-                    "\n" => {
-                        let buffer = BufReader::new(rx);
-                        let stream = LinesStream::new(buffer.lines());
-                        Box::new(stream.map_ok(Bytes::from))
-                    }
-                    _ => {
-                        let buffer = BufReader::new(rx);
-                        let delimiters = channel.delimiters.clone().into_bytes();
-                        let codec = AnyDelimiterCodec::new(delimiters, vec![]);
-                        let stream = FramedRead::new(buffer, codec);
-                        Box::new(stream.map_err(IOError::other))
-                    }
-                };
 
             Ok(RunningProcess {
                 child: Some(child),
-                sock_rx,
                 proc_tx: Box::new(tx),
-                proc_rx,
-                kill_rx,
+                proc_rx: child_rx(rx, channel),
+                err_rx,
+                frame_rx: Box::new(futures::stream::empty()),
+                frame_tx: None,
+            })
+        }
+        Source::WebSocket(cmd, url) => {
+            let url = url.clone();
+            let mut child = cmd.spawn()?;
+            let err_rx = stderr_lines(&mut child);
+
+            let stream =
+                attach_socket(channel, &mut child, url.clone(), || connect_ws(&url)).await?;
+            let (tx, rx) = stream.split();
+            let frame_rx = rx
+                .map_err(IOError::other)
+                .try_filter_map(|msg| future::ready(Ok(from_ws(msg))));
+            let frame_tx = tx
+                .sink_map_err(IOError::other)
+                .with(|msg| future::ready(Ok(to_ws(msg))));
+
+            Ok(RunningProcess {
+                child: Some(child),
+                proc_rx: Box::new(futures::stream::empty()),
+                proc_tx: Box::new(tokio::io::sink()),
+                err_rx,
+                frame_rx: Box::new(frame_rx),
+                frame_tx: Some(Box::new(frame_tx)),
             })
         }
     }
 }
 
+/// Connect to the websocket server of the child
+async fn connect_ws(url: &str) -> IOResult<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+    let (stream, _) = connect_async(url).await.map_err(IOError::other)?;
+    Ok(stream)
+}
+
+/// Convert a websocket frame from the child, skipping control frames
+fn from_ws(msg: WsMessage) -> Option<Message> {
+    match msg {
+        WsMessage::Text(text) => Some(Message::text(text)),
+        WsMessage::Binary(data) => Some(Message::binary(data)),
+        _ => None,
+    }
+}
+
+/// Convert a message to a websocket frame for the child
+fn to_ws(msg: Message) -> WsMessage {
+    match msg.is_binary() {
+        true => WsMessage::binary(msg.into_bytes()),
+        false => WsMessage::text(msg.to_str().unwrap_or_default()),
+    }
+}
+
+/// Connect to the socket of the child, once it is ready
+async fn attach_socket<S, F, Fut>(
+    channel: &Channel,
+    child: &mut Child,
+    addr: String,
+    connect: F,
+) -> AppResult<S>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = IOResult<S>>,
+{
+    if let Some(attach_delay) = channel.attach_delay {
+        tracing::debug!("delaying socket connect for {} seconds", attach_delay);
+        sleep(Duration::from_secs(attach_delay)).await;
+    }
+
+    let mut stdout = child.stdout.take().map(BufReader::new);
+    let startup = async {
+        if let Some(ref ready_msg) = channel.ready_msg {
+            let stdout = stdout
+                .as_mut()
+                .ok_or(AppError::ProcessStdIOError("stdout"))?;
+            wait_ready(stdout, ready_msg).await?;
+        }
+        retry_connect(child, &addr, connect).await
+    };
+    let startup_timeout = Duration::from_secs(channel.startup_timeout);
+    let stream = timeout(startup_timeout, startup)
+        .await
+        .map_err(|_| AppError::NetworkError(addr.clone(), "startup timeout".to_string()))
+        .flatten();
+
+    let stream = match stream {
+        Ok(stream) => stream,
+        Err(e) => {
+            // Do not leave a child behind that was never connected to
+            let _ = child.kill().await;
+            return Err(e);
+        }
+    };
+
+    // Keep reading stdout, so that the child does not block on a full pipe
+    if let Some(stdout) = stdout {
+        tokio::spawn(drain_stdout(stdout).in_current_span());
+    }
+
+    tracing::debug!("connected to childprocess at {}", addr);
+    Ok(stream)
+}
+
+/// Stream items from the output of the child, split by gwsocket frames or delimiters
+fn child_rx<R>(rx: R, channel: &Channel) -> FromProcessRxAny
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let buffer = BufReader::new(rx);
+    match channel.framing.process_to_socket() {
+        Some(Frame::GWSocket) => {
+            return Box::new(FramedRead::new(buffer, GWSocketCodec::default()));
+        }
+        Some(frame @ (Frame::LenPrefix | Frame::Length)) => {
+            return Box::new(FramedRead::new(buffer, LenPrefixCodec::new(frame)));
+        }
+        Some(frame @ (Frame::MsgPack | Frame::CBOR)) => {
+            return Box::new(FramedRead::new(buffer, ValueCodec::new(frame)));
+        }
+        _ => {}
+    }
+    match channel.delimiters.as_str() {
+        "" => {
+            let stream = FramedRead::new(buffer, BytesCodec::new());
+            Box::new(stream.map_ok(Bytes::from))
+        }
+        "\n" => {
+            let stream = LinesStream::new(buffer.lines());
+            Box::new(stream.map_ok(Bytes::from))
+        }
+        _ => {
+            let delimiters = channel.delimiters.as_bytes().to_vec();
+            let codec = AnyDelimiterCodec::new(delimiters, vec![]);
+            let stream = FramedRead::new(buffer, codec);
+            Box::new(stream.map_err(IOError::other))
+        }
+    }
+}
+
+/// Wait for the child to print the ready message on stdout
+///
+/// Output after the ready message is left in the reader.
+async fn wait_ready<R>(stdout: &mut BufReader<R>, ready_msg: &str) -> AppResult<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        if stdout.read_until(b'\n', &mut line).await? == 0 {
+            return Err(AppError::StreamClosed("process stdout"));
+        }
+        let line = line.strip_suffix(b"\n").unwrap_or(&line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line == ready_msg.as_bytes() {
+            tracing::debug!("child is ready");
+            return Ok(());
+        }
+    }
+}
+
+/// Log the stdout of a child connected over a socket, until it is closed
+async fn drain_stdout<R>(stdout: BufReader<R>)
+where
+    R: AsyncRead + Unpin,
+{
+    let mut lines = SplitStream::new(stdout.split(b'\n'));
+    while let Some(Ok(line)) = lines.next().await {
+        tracing::debug!("stdout: {}", String::from_utf8_lossy(&line));
+    }
+}
+
+/// Connect to the child, retrying with exponential backoff until it accepts connections
+async fn retry_connect<S, F, Fut>(child: &mut Child, addr: &str, connect: F) -> AppResult<S>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = IOResult<S>>,
+{
+    let mut backoff = MIN_CONNECT_BACKOFF;
+
+    loop {
+        match connect().await {
+            Ok(stream) => return Ok(stream),
+            // Stop retrying if the child is gone
+            Err(e) if !matches!(child.try_wait(), Ok(None)) => {
+                return Err(AppError::NetworkError(
+                    addr.to_string(),
+                    e.kind().to_string(),
+                ));
+            }
+            Err(e) => {
+                tracing::trace!(
+                    "failed to connect to child: {}, retrying in {:?}",
+                    e,
+                    backoff
+                );
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_CONNECT_BACKOFF);
+            }
+        }
+    }
+}
+
+const MIN_CONNECT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Stream stderr lines of the child, if captured
+///
+/// Lines are decoded lossily, so that invalid UTF-8 does not end the stream.
+fn stderr_lines(child: &mut Child) -> FromProcessErrAny {
+    match child.stderr.take() {
+        Some(stderr) => {
+            let stream = SplitStream::new(BufReader::new(stderr).split(b'\n'));
+            Box::new(stream.map_ok(|line| {
+                let line = line.strip_suffix(b"\r").unwrap_or(&line);
+                String::from_utf8_lossy(line).into_owned()
+            }))
+        }
+        None => Box::new(futures::stream::empty()),
+    }
+}
+
 struct RunningProcess {
     child: Option<Child>,
-    sock_rx: ToProcessRxStream,
     proc_rx: FromProcessRxAny,
     proc_tx: FromProcessTxAny,
-    kill_rx: ShutdownRxStream,
+    err_rx: FromProcessErrAny,
+    // Frames of a child speaking websocket
+    frame_rx: FromProcessFrameAny,
+    frame_tx: Option<ToProcessFrameAny>,
 }
 
 impl RunningProcess {
     /// Send a message to the child process
     pub async fn write_child(&mut self, msg: Message, is_binary: bool) -> IOResult<()> {
-        if is_binary {
+        if let Some(ref mut frame_tx) = self.frame_tx {
+            frame_tx.send(msg).await
+        } else if is_binary {
             self.proc_tx.write_all(msg.as_bytes()).await
         } else {
             self.proc_tx
@@ -197,25 +556,51 @@ impl RunningProcess {
 #[cfg(test)]
 mod tests {
 
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
     use clap::Parser;
-    use futures::StreamExt;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, UnixListener};
+    use tokio::sync::{Barrier, broadcast::error::RecvError};
+    use tokio::time::{Duration, sleep, timeout};
     use tokio_stream::wrappers::BroadcastStream;
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
     use warp::ws::Message;
 
     use super::{handle, spawn};
     use crate::{
-        channel::Channel,
+        channel::{Channel, Source},
         cli::Config,
         envvars::CGIEnv,
-        message::Address,
-        types::{Event, EventTx},
+        error::AppError,
+        message::{Address, serialize},
+        types::{
+            ControlEvent, Event, EventTx, ExitReason, Frame, Header, Limit, Recipients,
+            SharedGroups,
+        },
     };
 
     fn create_channel(args: &'static str) -> Channel {
-        let config = Config::parse_from(args.split_whitespace());
+        create_channel_from(args.split_whitespace())
+    }
+
+    fn create_channel_from<'a>(args: impl IntoIterator<Item = &'a str>) -> Channel {
+        let config = Config::parse_from(args);
         Channel::new(&config, None, "room1", CGIEnv::default(), None)
     }
 
+    fn create_tcp_channel_from<'a>(args: impl IntoIterator<Item = &'a str>) -> Channel {
+        let config = Config::parse_from(args);
+        Channel::new(&config, Some(free_port()), "room1", CGIEnv::default(), None)
+    }
+
+    fn free_port() -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
     fn create_channel_with_event_tx(args: &'static str, event_tx: EventTx) -> Channel {
         let mut channel = create_channel(args);
         channel.give_sender(event_tx);
@@ -232,6 +617,412 @@ mod tests {
         assert_eq!(child.wait().await.ok().unwrap().code(), Some(0));
     }
 
+    #[tokio::test]
+    async fn test_spawn_tcp_stops_retrying_when_child_exits() {
+        let mut channel = create_tcp_channel_from(["scalesocket", "--tcp", "false"]);
+
+        let result = timeout(Duration::from_secs(5), spawn(&mut channel)).await;
+
+        assert!(matches!(result, Ok(Err(AppError::NetworkError(..)))));
+    }
+
+    #[tokio::test]
+    async fn test_spawn_tcp_startup_timeout() {
+        let mut channel = create_tcp_channel_from([
+            "scalesocket",
+            "--tcp",
+            "--startuptimeout=0",
+            "sleep",
+            "--",
+            "1",
+        ]);
+
+        let result = spawn(&mut channel).await;
+
+        assert!(matches!(result, Err(AppError::NetworkError(_, e)) if e == "startup timeout"));
+    }
+
+    #[tokio::test]
+    async fn test_spawn_tcp_kills_child_after_startup_timeout() {
+        let path = std::env::temp_dir().join(format!("scalesocket-test-{}", std::process::id()));
+        let script = format!("sleep 1; touch {}", path.display());
+        let mut channel = create_tcp_channel_from([
+            "scalesocket",
+            "--tcp",
+            "--startuptimeout=0",
+            "sh",
+            "--",
+            "-c",
+            &script,
+        ]);
+
+        let result = spawn(&mut channel).await;
+        sleep(Duration::from_millis(1500)).await;
+
+        assert!(result.is_err());
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_spawn_tcp_waits_for_ready_msg() {
+        let mut channel = create_tcp_channel_from([
+            "scalesocket",
+            "--tcp",
+            "--readymsg=READY",
+            "sh",
+            "--",
+            "-c",
+            "echo foo",
+        ]);
+
+        let result = spawn(&mut channel).await;
+
+        assert!(matches!(result, Err(AppError::StreamClosed(_))));
+    }
+
+    #[tokio::test]
+    async fn test_spawn_unix_passes_socket_path() {
+        let mut channel = create_channel_from([
+            "scalesocket",
+            "--unix",
+            "--readymsg=READY",
+            "sh",
+            "--",
+            "-c",
+            r#"test -n "$SOCKET_PATH" && echo READY"#,
+        ]);
+
+        let result = spawn(&mut channel).await;
+
+        // Child signaled readiness, but did not bind the socket
+        assert!(matches!(result, Err(AppError::NetworkError(..))));
+    }
+
+    #[tokio::test]
+    async fn test_handle_unix_echo() {
+        let mut channel = create_channel_from([
+            "scalesocket",
+            "--unix",
+            "sh",
+            "--",
+            "-c",
+            r#"touch "$SOCKET_PATH.started"; exec sleep 10"#,
+        ]);
+        let Some(Source::Unix(_, ref path)) = channel.source else {
+            panic!("expected unix source");
+        };
+        let path = path.clone();
+        let started = path.with_extension("sock.started");
+        let kill_tx = channel.take_senders().kill_tx;
+        let mut proc_rx = channel.cast_tx.subscribe();
+        channel.tx.send(Message::text("foo")).await.unwrap();
+        let handle = tokio::spawn(handle(channel, None));
+
+        // Echo as the peer of the child, listening once spawning has removed stale sockets
+        while !started.exists() {
+            sleep(Duration::from_millis(10)).await;
+        }
+        let listener = UnixListener::bind(&path).unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut received = [0; 4];
+        stream.read_exact(&mut received).await.unwrap();
+        stream.write_all(&received).await.unwrap();
+
+        let output = proc_rx.recv().await.ok();
+        drop(stream);
+        kill_tx.send(()).ok();
+        handle.await.ok();
+        std::fs::remove_file(started).ok();
+
+        assert_eq!(&received, b"foo\n");
+        assert_eq!(output, Some(Message::text("foo").broadcast()));
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket_removed_on_drop() {
+        let channel = create_channel("scalesocket --unix true");
+        let Some(Source::Unix(_, ref path)) = channel.source else {
+            panic!("expected unix source");
+        };
+        let path = path.clone();
+        std::fs::write(&path, "").unwrap();
+
+        drop(channel);
+
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_spawn_ws_stops_retrying_when_child_exits() {
+        let mut channel = create_tcp_channel_from(["scalesocket", "--ws", "false"]);
+        let expected = format!("ws://127.0.0.1:{}/", channel.port.unwrap());
+
+        let result = timeout(Duration::from_secs(5), spawn(&mut channel)).await;
+
+        assert!(matches!(result, Ok(Err(AppError::NetworkError(url, _))) if url == expected));
+    }
+
+    #[tokio::test]
+    async fn test_handle_ws_echo() {
+        let mut channel = create_tcp_channel_from(["scalesocket", "--ws", "sleep", "--", "10"]);
+        let listener = TcpListener::bind(("127.0.0.1", channel.port.unwrap()))
+            .await
+            .unwrap();
+        let kill_tx = channel.take_senders().kill_tx;
+        let mut proc_rx = channel.cast_tx.subscribe();
+        channel.tx.send(Message::text("foo")).await.unwrap();
+        channel.tx.send(Message::binary([1, 2])).await.unwrap();
+        let handle = tokio::spawn(handle(channel, None));
+
+        // Echo as the websocket server of the child
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let received = [
+            ws.next().await.unwrap().unwrap(),
+            ws.next().await.unwrap().unwrap(),
+        ];
+        for msg in received.clone() {
+            ws.send(msg).await.unwrap();
+        }
+
+        let output = [proc_rx.recv().await.ok(), proc_rx.recv().await.ok()];
+        drop(ws);
+        kill_tx.send(()).ok();
+        handle.await.ok();
+
+        assert_eq!(
+            received,
+            [WsMessage::text("foo"), WsMessage::binary(vec![1, 2])]
+        );
+        assert_eq!(
+            output,
+            [
+                Some(Message::text("foo").broadcast()),
+                Some(Message::binary([1, 2]).broadcast())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_sock_frame_preserves_type() {
+        let mut channel = create_tcp_channel_from(["scalesocket", "--ws", "cat"]);
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        channel.write_sock_frame(Message::binary([1, 2])).await;
+        channel.write_sock_frame(Message::text("foo")).await;
+
+        assert_eq!(
+            proc_rx.try_recv().ok(),
+            Some(Message::binary([1, 2]).broadcast())
+        );
+        assert_eq!(
+            proc_rx.try_recv().ok(),
+            Some(Message::text("foo").broadcast())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_lag_drop() {
+        let channel = create_channel("scalesocket --serverbuffer=1 printf -- a\\nb\\nc");
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        handle(channel, None).await.ok();
+
+        assert!(matches!(proc_rx.recv().await, Err(RecvError::Lagged(_))));
+    }
+
+    #[tokio::test]
+    async fn test_handle_lag_block() {
+        let channel =
+            create_channel("scalesocket --serverbuffer=1 --lag=block printf -- a\\nb\\nc");
+        let mut proc_rx = channel.cast_tx.subscribe();
+        let drained = channel.drained.clone();
+
+        let handle = tokio::spawn(handle(channel, None));
+        sleep(Duration::from_millis(100)).await;
+
+        for msg in ["a", "b", "c"] {
+            assert_eq!(
+                proc_rx.recv().await.ok(),
+                Some(Message::text(msg).broadcast())
+            );
+            drained.notify_one();
+        }
+        handle.await.ok();
+    }
+
+    #[tokio::test]
+    async fn test_handle_lag_block_stderr_and_exit() {
+        let channel = create_channel_from([
+            "scalesocket",
+            "--serverbuffer=1",
+            "--lag=block",
+            "--stderr=clients",
+            "--exitmsg=bye",
+            "sh",
+            "--",
+            "-c",
+            "echo foo; echo bar >&2",
+        ]);
+        let mut proc_rx = channel.cast_tx.subscribe();
+        let drained = channel.drained.clone();
+
+        let handle = tokio::spawn(handle(channel, None));
+        sleep(Duration::from_millis(100)).await;
+
+        // Stderr and exit notices wait for the client like regular output
+        assert_eq!(proc_rx.len(), 1);
+
+        let mut outputs = Vec::new();
+        for _ in 0..4 {
+            outputs.push(proc_rx.recv().await.unwrap());
+            drained.notify_one();
+        }
+        handle.await.ok();
+
+        assert!(outputs.contains(&Message::text(r#"{"data":"bar","t":"Stderr"}"#).broadcast()));
+        assert_eq!(outputs[2], Message::text("bye").broadcast());
+        assert_eq!(
+            outputs[3],
+            Message::close_with(1000u16, "process exited").broadcast()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_closes_clients_on_exit() {
+        let channel = create_channel("scalesocket false");
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        handle(channel, None).await.ok();
+        let output = proc_rx.recv().await.ok();
+
+        assert_eq!(
+            output,
+            Some(Message::close_with(1011u16, "process exited with code 1").broadcast())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_restarts_process_on_failure() {
+        let (event_tx, mut event_rx) = tokio::sync::mpsc::channel::<Event>(16);
+        let mut channel = create_channel_from([
+            "scalesocket",
+            "--restart=on-failure",
+            "--restartmax=2",
+            "--restartdelay=0",
+            "sh",
+            "--",
+            "-c",
+            "exit 3",
+        ]);
+        channel.give_sender(event_tx);
+        let _proc_rx = channel.cast_tx.subscribe();
+
+        let result = handle(channel, None).await;
+
+        assert_eq!(result.ok(), Some((Some(3), ExitReason::Exited)));
+        for attempt in 1..=2 {
+            let event = event_rx.recv().await.unwrap();
+            let Event::ProcessRestart {
+                code, attempt: a, ..
+            } = event
+            else {
+                panic!("expected ProcessRestart");
+            };
+            assert_eq!((code, a), (Some(3), attempt));
+        }
+        assert!(matches!(event_rx.try_recv(), Ok(Event::ProcessExit { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_handle_does_not_restart_without_clients() {
+        let (event_tx, mut event_rx) = tokio::sync::mpsc::channel::<Event>(16);
+        let channel = create_channel_with_event_tx(
+            "scalesocket --restart=always --restartdelay=0 true",
+            event_tx,
+        );
+
+        let result = handle(channel, None).await;
+
+        assert_eq!(result.ok(), Some((Some(0), ExitReason::Exited)));
+        assert!(matches!(event_rx.try_recv(), Ok(Event::ProcessExit { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_handle_stops_process_with_signal() {
+        let mut channel = create_channel("scalesocket sleep -- 10");
+        let kill_tx = channel.take_senders().kill_tx;
+
+        let stop = async {
+            sleep(Duration::from_millis(100)).await;
+            kill_tx.send(()).ok();
+        };
+        let (result, _) = tokio::join!(handle(channel, None), stop);
+
+        assert_eq!(result.ok(), Some((None, ExitReason::Stopped)));
+    }
+
+    #[tokio::test]
+    async fn test_handle_kills_process_after_stop_timeout() {
+        let mut channel = create_channel_from([
+            "scalesocket",
+            "--stoptimeout=0",
+            "sh",
+            "--",
+            "-c",
+            "trap '' TERM; sleep 10",
+        ]);
+        let kill_tx = channel.take_senders().kill_tx;
+
+        let stop = async {
+            sleep(Duration::from_millis(100)).await;
+            kill_tx.send(()).ok();
+        };
+        let (result, _) = tokio::join!(handle(channel, None), stop);
+
+        assert_eq!(result.ok(), Some((None, ExitReason::Killed)));
+    }
+
+    #[tokio::test]
+    async fn test_handle_reports_cpu_limit() {
+        let channel = create_channel_from([
+            "scalesocket",
+            "--limitcpu=1",
+            "sh",
+            "--",
+            "-c",
+            "while :; do :; done",
+        ]);
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        let result = handle(channel, None).await;
+        let output = proc_rx.recv().await.ok();
+
+        assert_eq!(result.ok(), Some((None, ExitReason::Limit(Limit::Cpu))));
+        assert_eq!(
+            output,
+            Some(Message::close_with(1011u16, "process exceeded cpu limit").broadcast())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_spawn_applies_rlimits() {
+        let channel = create_channel_from([
+            "scalesocket",
+            "--limitfiles=16",
+            "sh",
+            "--",
+            "-c",
+            "ulimit -n",
+        ]);
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        handle(channel, None).await.ok();
+        let output = proc_rx.recv().await.ok();
+
+        assert_eq!(output, Some(Message::text("16").broadcast()));
+    }
+
     #[tokio::test]
     async fn test_spawn_passes_cgi_env() {
         let channel = create_channel("scalesocket --passenv= printenv");
@@ -249,6 +1040,7 @@ mod tests {
                 Message::text("QUERY_STRING=").broadcast(),
                 Message::text("REMOTE_ADDR=").broadcast(),
                 Message::text("ROOM=").broadcast(),
+                Message::close_with(1000u16, "process exited").broadcast(),
             ]
         );
     }
@@ -327,7 +1119,6 @@ mod tests {
             r"\001\000\000\000", // type
             r"\003\000\000\000", // payload length
             r"abc",              // payload
-            r"\n"
         ));
         let mut proc_rx = channel.cast_tx.subscribe();
 
@@ -337,11 +1128,342 @@ mod tests {
         assert_eq!(output, Some(Message::text("abc").to(2)));
     }
 
+    #[tokio::test]
+    async fn test_handle_process_output_framed_gwsocket_with_newline() {
+        let channel = create_channel(concat!(
+            "scalesocket --frame=gwsocket printf -- ",
+            r"\002\000\000\000", // id
+            r"\001\000\000\000", // type
+            r"\003\000\000\000", // payload length
+            r"a\nb",             // payload
+            r"\000\000\000\000", // id
+            r"\001\000\000\000", // type
+            r"\001\000\000\000", // payload length
+            r"c",                // payload
+        ));
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        handle(channel, None).await.ok();
+
+        assert_eq!(proc_rx.recv().await.ok(), Some(Message::text("a\nb").to(2)));
+        assert_eq!(
+            proc_rx.recv().await.ok(),
+            Some(Message::text("c").broadcast())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_process_output_framed_lenprefix() {
+        let channel = create_channel(concat!(
+            "scalesocket --frame=lenprefix printf -- ",
+            r"\000\000\000\002", // payload length
+            r"\000\000\000\000", // id
+            r"\000\n",           // payload
+        ));
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        handle(channel, None).await.ok();
+        let output = proc_rx.recv().await.ok();
+
+        assert_eq!(output, Some(Message::binary(*b"\0\n").broadcast()));
+    }
+
+    #[tokio::test]
+    async fn test_handle_process_output_framed_length() {
+        let channel = create_channel(concat!(
+            "scalesocket --frame=length printf -- ",
+            r"\000\000\000\002", // payload length
+            r"\000\n",           // payload
+        ));
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        handle(channel, None).await.ok();
+        let output = proc_rx.recv().await.ok();
+
+        assert_eq!(output, Some(Message::binary(*b"\0\n").broadcast()));
+    }
+
+    #[tokio::test]
+    async fn test_handle_process_output_framed_msgpack() {
+        let channel = create_channel(concat!(
+            "scalesocket --frame=msgpack printf -- ",
+            r"\202",        // map of two
+            r"\243_to\002", // "_to": 2
+            r"\241a\012",   // "a": 10
+        ));
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        handle(channel, None).await.ok();
+        let output = proc_rx.recv().await.ok();
+
+        let expected = b"\x82\xa3_to\x02\xa1a\x0a";
+        assert_eq!(output, Some(Message::binary(*expected).to(2)));
+    }
+
+    #[tokio::test]
+    async fn test_handle_process_output_to_group() {
+        let channel = create_channel_from([
+            "scalesocket",
+            "--serverframe=json",
+            "printf",
+            "--",
+            r#"{"_group_add":"red","_conn":3}\n{"_to_group":"red"}\n"#,
+        ]);
+        let groups = channel.groups.clone();
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        handle(channel, None).await.ok();
+        let output = proc_rx.recv().await.ok();
+
+        let header = Header {
+            to: Some(Recipients::Many(vec![3])),
+            ..Default::default()
+        };
+        let expected = Message::text(r#"{"_to_group":"red"}"#).header(header);
+        assert_eq!(output, Some(expected));
+        assert_eq!(groups.read().unwrap().members("red"), vec![3]);
+    }
+
+    #[tokio::test]
+    async fn test_handle_process_output_ignores_invalid_except() {
+        let channel = create_channel_from([
+            "scalesocket",
+            "--serverframe=json",
+            "printf",
+            "--",
+            r#"{"_to":5,"_except":"bob"}\n"#,
+        ]);
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        handle(channel, None).await.ok();
+        let output = proc_rx.recv().await.ok();
+
+        let expected = Message::text(r#"{"_to":5,"_except":"bob"}"#).to(5);
+        assert_eq!(output, Some(expected));
+    }
+
+    #[tokio::test]
+    async fn test_handle_process_output_ignores_invalid_group_fields() {
+        let channel = create_channel_from([
+            "scalesocket",
+            "--serverframe=json",
+            "printf",
+            "--",
+            r#"{"_to":5,"_conn":"x","_to_group":1}\n"#,
+        ]);
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        handle(channel, None).await.ok();
+        let output = proc_rx.recv().await.ok();
+
+        let expected = Message::text(r#"{"_to":5,"_conn":"x","_to_group":1}"#).to(5);
+        assert_eq!(output, Some(expected));
+    }
+
+    #[tokio::test]
+    async fn test_handle_process_output_envelope() {
+        let channel = create_channel_from([
+            "scalesocket",
+            "--frame=envelope",
+            "echo",
+            "--",
+            r#"{"_to":5,"data":"hello"}"#,
+        ]);
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        handle(channel, None).await.ok();
+        let output = proc_rx.recv().await.ok();
+
+        assert_eq!(output, Some(Message::text("hello").to(5)));
+    }
+
+    #[tokio::test]
+    async fn test_handle_process_output_envelope_binary() {
+        let channel = create_channel_from([
+            "scalesocket",
+            "--frame=envelope",
+            "echo",
+            "--",
+            r#"{"_binary":true,"data":"AAE="}"#,
+        ]);
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        handle(channel, None).await.ok();
+        let output = proc_rx.recv().await.ok();
+
+        let header = Header {
+            is_binary: true,
+            ..Default::default()
+        };
+        assert_eq!(output, Some(Message::binary([0, 1]).header(header)));
+    }
+
+    #[tokio::test]
+    async fn test_handle_process_output_strip() {
+        let channel = create_channel_from([
+            "scalesocket",
+            "--serverframe=json",
+            "--strip",
+            "echo",
+            "--",
+            r#"{"_to":5,"_cache":true,"_from":1,"foo":1}"#,
+        ]);
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        handle(channel, None).await.ok();
+        let output = proc_rx.recv().await.ok();
+
+        let header = Header {
+            to: Some(Recipients::One(5)),
+            is_cache: true,
+            ..Default::default()
+        };
+        let expected = Message::text(r#"{"_from":1,"foo":1}"#).header(header);
+        assert_eq!(output, Some(expected));
+    }
+
+    #[tokio::test]
+    async fn test_handle_process_output_strip_list() {
+        let channel = create_channel_from([
+            "scalesocket",
+            "--serverframe=json",
+            "--strip=_to,_from",
+            "echo",
+            "--",
+            r#"{"_to":5,"_from":1,"foo":1}"#,
+        ]);
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        handle(channel, None).await.ok();
+        let output = proc_rx.recv().await.ok();
+
+        assert_eq!(output, Some(Message::text(r#"{"foo":1}"#).to(5)));
+    }
+
+    #[tokio::test]
+    async fn test_handle_resends_bindmsg_on_restart() {
+        let mut channel = create_channel_from([
+            "scalesocket",
+            "--restart=always",
+            "--restartmax=1",
+            "--restartdelay=0",
+            "head",
+            "--",
+            "-n",
+            "1",
+        ]);
+        let bind_tx = channel.take_bind_sender();
+        let _senders = channel.take_senders();
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        let bind_msg = Some("bound".to_string());
+        let barrier = Arc::new(Barrier::new(1));
+        let binding = (
+            "room1".to_string(),
+            bind_msg,
+            None,
+            SharedGroups::default(),
+            barrier,
+        );
+        bind_tx.send(binding).unwrap();
+        handle(channel, None).await.ok();
+
+        let expected = Some(Message::text("bound").broadcast());
+        assert_eq!(proc_rx.recv().await.ok(), expected);
+        assert_eq!(proc_rx.recv().await.ok(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_handle_process_output_kick() {
+        let channel = create_channel_from([
+            "scalesocket",
+            "--serverframe=json",
+            "echo",
+            "--",
+            r#"{"_kick":5,"_code":4000,"_reason":"cheater"}"#,
+        ]);
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        handle(channel, None).await.ok();
+        let output = proc_rx.recv().await.ok();
+
+        assert_eq!(output, Some(Message::close_with(4000u16, "cheater").to(5)));
+    }
+
+    #[tokio::test]
+    async fn test_handle_process_control_kick() {
+        let channel = create_channel_from([
+            "scalesocket",
+            "--control",
+            "sh",
+            "--",
+            "-c",
+            r#"echo '{"t":"Kick","conn":5,"reason":"cheater"}' >&$CONTROL_FD"#,
+        ]);
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        handle(channel, None).await.ok();
+        let output = proc_rx.recv().await.ok();
+
+        assert_eq!(output, Some(Message::close_with(1008u16, "cheater").to(5)));
+    }
+
+    #[tokio::test]
+    async fn test_handle_process_control_events() {
+        let mut channel = create_channel_from([
+            "scalesocket",
+            "--control",
+            "sh",
+            "--",
+            "-c",
+            "head -n 1 <&$CONTROL_FD",
+        ]);
+        let senders = channel.take_senders();
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        let env = BTreeMap::from([("QUERY_NAME".to_string(), "alice".to_string())]);
+        senders
+            .control_tx
+            .send(ControlEvent::Join { conn: 5, env })
+            .unwrap();
+        handle(channel, None).await.ok();
+        let output = proc_rx.recv().await.ok();
+
+        assert_eq!(
+            output,
+            Some(
+                Message::text(r#"{"t":"Join","conn":5,"env":{"QUERY_NAME":"alice"}}"#).broadcast()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_process_output_kick_invalid_code() {
+        let channel = create_channel_from([
+            "scalesocket",
+            "--serverframe=json",
+            "echo",
+            "--",
+            r#"{"_kick":[5,6],"_code":1005}"#,
+        ]);
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        handle(channel, None).await.ok();
+        let output = proc_rx.recv().await.ok();
+
+        let header = Header {
+            to: Some(Recipients::Many(vec![5, 6])),
+            ..Default::default()
+        };
+        let expected = Message::close_with(1008u16, "kicked by process").header(header);
+        assert_eq!(output, Some(expected));
+    }
+
     #[test]
     fn test_handle_process_output_metadata_json() {
         let rt = tokio::runtime::Runtime::new().unwrap();
 
-        let (event_tx, mut event_rx) = tokio::sync::mpsc::unbounded_channel::<Event>();
+        let (event_tx, mut event_rx) = tokio::sync::mpsc::channel::<Event>(16);
         let channel = create_channel_with_event_tx(
             r#"scalesocket --serverframe=json echo -- {"_meta": true, "foo": "bar"}"#,
             event_tx,
@@ -358,6 +1480,74 @@ mod tests {
         assert_eq!(value["foo"], "bar");
     }
 
+    #[tokio::test]
+    async fn test_handle_process_stderr_to_clients() {
+        let channel = create_channel_from([
+            "scalesocket",
+            "--stderr=clients",
+            "sh",
+            "--",
+            "-c",
+            "echo foo >&2",
+        ]);
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        handle(channel, None).await.ok();
+        let output = proc_rx.recv().await.ok();
+
+        assert_eq!(
+            output,
+            Some(Message::text(r#"{"data":"foo","t":"Stderr"}"#).broadcast())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_process_stderr_invalid_utf8() {
+        let channel = create_channel_from([
+            "scalesocket",
+            "--stderr=clients",
+            "sh",
+            "--",
+            "-c",
+            r"printf 'a\377\nb\n' >&2",
+        ]);
+        let mut proc_rx = channel.cast_tx.subscribe();
+
+        handle(channel, None).await.ok();
+
+        assert_eq!(
+            proc_rx.recv().await.ok(),
+            Some(Message::text("{\"data\":\"a\u{FFFD}\",\"t\":\"Stderr\"}").broadcast())
+        );
+        assert_eq!(
+            proc_rx.recv().await.ok(),
+            Some(Message::text(r#"{"data":"b","t":"Stderr"}"#).broadcast())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_process_stderr_to_file() {
+        let dir = std::env::temp_dir().join("scalesocket_test_stderr");
+        std::fs::create_dir_all(&dir).unwrap();
+        let _ = std::fs::remove_file(dir.join("room1.log"));
+
+        let stderr_dir = format!("--stderrdir={}", dir.display());
+        let channel = create_channel_from([
+            "scalesocket",
+            "--stderr=file",
+            &stderr_dir,
+            "sh",
+            "--",
+            "-c",
+            "echo foo >&2; echo bar >&2",
+        ]);
+
+        handle(channel, None).await.ok();
+        let output = std::fs::read_to_string(dir.join("room1.log")).unwrap();
+
+        assert_eq!(output, "foo\nbar\n");
+    }
+
     #[tokio::test]
     async fn test_handle_process_input() {
         let channel = create_channel("scalesocket head -- -n 1");
@@ -365,7 +1555,7 @@ mod tests {
         let sock_tx = channel.tx.clone();
 
         let send = async {
-            sock_tx.send(Message::text("foo\n")).ok();
+            sock_tx.send(Message::text("foo\n")).await.ok();
             Ok(())
         };
         let handle = handle(channel, None);
@@ -376,6 +1566,25 @@ mod tests {
         assert_eq!(output, Some(Message::text("foo").broadcast()));
     }
 
+    #[tokio::test]
+    async fn test_handle_writes_lifecycle_messages_first() {
+        let channel = create_channel("scalesocket head -- -n 2");
+        let mut proc_rx = channel.cast_tx.subscribe();
+        channel.tx.send(Message::text("msg")).await.unwrap();
+        channel.lifecycle_tx.send(Message::text("join")).unwrap();
+
+        handle(channel, None).await.ok();
+
+        assert_eq!(
+            proc_rx.recv().await.ok(),
+            Some(Message::text("join").broadcast())
+        );
+        assert_eq!(
+            proc_rx.recv().await.ok(),
+            Some(Message::text("msg").broadcast())
+        );
+    }
+
     #[tokio::test]
     async fn test_handle_process_input_framed_json() {
         let channel = create_channel("scalesocket --frame=json head -- -n 1");
@@ -385,6 +1594,7 @@ mod tests {
         let send = async {
             sock_tx
                 .send(Message::text("{'id': 1, 'msg': 'foo'}\n"))
+                .await
                 .ok();
             Ok(())
         };
@@ -406,7 +1616,7 @@ mod tests {
         let sock_tx = channel.tx.clone();
 
         let send = async {
-            sock_tx.send(Message::text("{'msg': 'foo'}\n")).ok();
+            sock_tx.send(Message::text("{'msg': 'foo'}\n")).await.ok();
             Ok(())
         };
         let handle = handle(channel, None);
@@ -416,4 +1626,42 @@ mod tests {
 
         assert_eq!(output, Some(Message::text("{'msg': 'foo'}").broadcast()));
     }
+
+    #[tokio::test]
+    async fn test_handle_process_input_framed_gwsocket() {
+        let channel = create_channel("scalesocket --frame=gwsocket head -- -c 15");
+        let mut proc_rx = channel.cast_tx.subscribe();
+        let sock_tx = channel.tx.clone();
+
+        let send = async {
+            let msg = serialize(Message::text("abc"), 2, Some(Frame::GWSocket)).unwrap();
+            sock_tx.send(msg).await.ok();
+            Ok(())
+        };
+        let handle = handle(channel, None);
+
+        tokio::try_join!(handle, send).ok();
+        let output = proc_rx.recv().await.ok();
+
+        assert_eq!(output, Some(Message::text("abc").to(2)));
+    }
+
+    #[tokio::test]
+    async fn test_handle_process_input_framed_lenprefix() {
+        let channel = create_channel("scalesocket --frame=lenprefix head -- -c 11");
+        let mut proc_rx = channel.cast_tx.subscribe();
+        let sock_tx = channel.tx.clone();
+
+        let send = async {
+            let msg = serialize(Message::binary(*b"a\nb"), 2, Some(Frame::LenPrefix)).unwrap();
+            sock_tx.send(msg).await.ok();
+            Ok(())
+        };
+        let handle = handle(channel, None);
+
+        tokio::try_join!(handle, send).ok();
+        let output = proc_rx.recv().await.ok();
+
+        assert_eq!(output, Some(Message::binary(*b"a\nb").to(2)));
+    }
 }
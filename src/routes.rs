@@ -63,8 +63,9 @@ pub fn socket(
             websocket.on_upgrade(move |ws| {
                 let ws = Box::new(ws);
                 let event = Event::Connect { env, room, ws };
-                tx.send(event).expect("Failed to send Connect event");
-                futures::future::ready(())
+                async move {
+                    tx.send(event).await.expect("Failed to send Connect event");
+                }
             })
         })
 }
@@ -137,7 +138,9 @@ pub fn metadata_api(
         .map(
             move |room: RoomID, metric: Option<String>| match metric.as_deref() {
                 Some("connections") => warp::reply::json(&metrics.get_room_connections(room)),
+                Some("state") => warp::reply::json(&metrics.get_room_state(&room)),
                 Some("metadata") => warp::reply::json(&metrics.get_room_metadata(&room)),
+                Some("groups") => warp::reply::json(&metrics.get_room_groups(&room)),
                 _ => warp::reply::json(&metrics.get_room(room)),
             },
         )
@@ -158,6 +161,7 @@ mod tests {
     use warp::test::{RequestBuilder, request};
 
     use super::*;
+    use crate::types::SharedGroups;
 
     fn ws_request(path: &'static str) -> RequestBuilder {
         request()
@@ -188,7 +192,7 @@ mod tests {
 
     #[tokio::test]
     async fn socket_rejects_reserved_room() {
-        let (tx, _) = tokio::sync::mpsc::unbounded_channel::<Event>();
+        let (tx, _) = tokio::sync::mpsc::channel::<Event>(16);
         let api = socket(tx, None).recover(handle_rejection);
 
         let ws = |path| ws_request(path).reply(&api);
@@ -202,7 +206,7 @@ mod tests {
 
     #[tokio::test]
     async fn socket_detects_browser_request() {
-        let (tx, _) = tokio::sync::mpsc::unbounded_channel::<Event>();
+        let (tx, _) = tokio::sync::mpsc::channel::<Event>(16);
         let api = socket(tx, None).recover(handle_rejection);
 
         let resp = browser_request("/room").reply(&api).await;
@@ -214,7 +218,7 @@ mod tests {
 
     #[tokio::test]
     async fn socket_accepts_allowed_room() {
-        let (tx, _) = tokio::sync::mpsc::unbounded_channel::<Event>();
+        let (tx, _) = tokio::sync::mpsc::channel::<Event>(16);
         let allowlist = vec!["allowed".to_string()];
         let api = socket(tx, Some(allowlist)).recover(handle_rejection);
 
@@ -265,10 +269,12 @@ mod tests {
         let body: Vec<Value> = serde_json::from_slice(resp.body()).unwrap();
         assert!(
             body.contains(
-                &json!({"name": "foo", "connections": 1, "metadata": json!({"bar": 123})})
+                &json!({"name": "foo", "connections": 1, "state": "active", "metadata": json!({"bar": 123}), "groups": null})
             )
         );
-        assert!(body.contains(&json!({"name": "bar", "connections": 1, "metadata": null})));
+        assert!(body.contains(
+            &json!({"name": "bar", "connections": 1, "state": "active", "metadata": null, "groups": null})
+        ));
     }
 
     #[tokio::test]
@@ -285,6 +291,45 @@ mod tests {
         assert_eq!(body["metadata"], json!({"bar": 123}));
     }
 
+    #[tokio::test]
+    async fn metadata_api_returns_room_groups() {
+        let metrics = Metrics::new(&mut None, true);
+        let groups = SharedGroups::default();
+        groups.write().unwrap().add("red", 3);
+        metrics.set_groups("foo", groups);
+
+        let api = metadata_api(metrics, true);
+
+        let resp = request()
+            .method("GET")
+            .path("/api/foo/groups")
+            .reply(&api)
+            .await;
+
+        assert!(resp.status().is_success());
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body, json!({"red": [3]}));
+    }
+
+    #[tokio::test]
+    async fn metadata_api_returns_idle_room_state() {
+        let metrics = Metrics::new(&mut None, true);
+        metrics.inc_ws_connections("foo");
+        metrics.dec_ws_connections("foo");
+        metrics.set_idle("foo", true);
+
+        let api = metadata_api(metrics, true);
+
+        let resp = request()
+            .method("GET")
+            .path("/api/foo/state")
+            .reply(&api)
+            .await;
+
+        assert!(resp.status().is_success());
+        assert_eq!(resp.body(), "\"idle\"");
+    }
+
     #[tokio::test]
     async fn metadata_api_returns_room_metric() {
         let metrics = Metrics::new(&mut None, true);
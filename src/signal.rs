@@ -12,5 +12,5 @@ pub async fn handle(routes_shutdown_tx: ShutdownTx, events_shutdown_tx: EventTx)
 
     tracing::info!("received signal, shutting down");
     let _ = routes_shutdown_tx.send(());
-    let _ = events_shutdown_tx.send(Event::Shutdown);
+    let _ = events_shutdown_tx.send(Event::Shutdown).await;
 }
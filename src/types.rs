@@ -1,10 +1,12 @@
 use {
     bytes::Bytes,
     heapless::HistoryBuf,
-    serde::Deserialize,
-    std::io::Result as IOResult,
-    tokio::sync::{broadcast, mpsc, oneshot},
-    tokio_stream::wrappers::UnboundedReceiverStream,
+    serde::{Deserialize, Deserializer, Serialize, de::IgnoredAny},
+    std::collections::{BTreeMap, BTreeSet},
+    std::io::{Error as IOError, Result as IOResult},
+    std::sync::{Arc, Mutex, RwLock},
+    tokio::sync::{Barrier, Notify, broadcast, mpsc, oneshot},
+    tokio_stream::wrappers::ReceiverStream,
     warp::ws::{Message, WebSocket},
 };
 
@@ -17,31 +19,127 @@ pub type PortID = u16;
 #[derive(Debug, Clone, Default, PartialEq, Deserialize)]
 pub struct Header {
     #[serde(rename = "_to")]
-    pub to: Option<ConnID>,
+    pub to: Option<Recipients>,
+    #[serde(rename = "_except", default, deserialize_with = "default_on_error")]
+    pub except: Option<Recipients>,
+    #[serde(rename = "_to_group", default, deserialize_with = "default_on_error")]
+    pub to_group: Option<String>,
+    #[serde(rename = "_group_add", default, deserialize_with = "default_on_error")]
+    pub group_add: Option<String>,
+    #[serde(
+        rename = "_group_remove",
+        default,
+        deserialize_with = "default_on_error"
+    )]
+    pub group_remove: Option<String>,
+    #[serde(rename = "_conn", default, deserialize_with = "default_on_error")]
+    pub conn: Option<ConnID>,
+    #[serde(rename = "_kick", default, deserialize_with = "default_on_error")]
+    pub kick: Option<Recipients>,
+    #[serde(rename = "_code", default, deserialize_with = "default_on_error")]
+    pub code: Option<u16>,
+    #[serde(rename = "_reason", default, deserialize_with = "default_on_error")]
+    pub reason: Option<String>,
     #[serde(rename = "_meta", default = "bool::default")]
     pub is_meta: bool,
     #[serde(rename = "_cache", default = "bool::default")]
     pub is_cache: bool,
+    #[serde(rename = "_binary", default, deserialize_with = "default_on_error")]
+    pub is_binary: bool,
+}
+
+/// Deserialize a header field, treating an invalid value as missing
+///
+/// Process output may use these names for its own data, which must not discard the routing of the message.
+fn default_on_error<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Tolerant<T> {
+        Valid(T),
+        Invalid(IgnoredAny),
+    }
+
+    Ok(match Tolerant::deserialize(deserializer)? {
+        Tolerant::Valid(value) => value,
+        Tolerant::Invalid(_) => T::default(),
+    })
 }
 
 impl Header {
     pub fn to(to: ConnID) -> Self {
         Header {
-            to: Some(to),
-            is_meta: false,
-            is_cache: false,
+            to: Some(Recipients::One(to)),
+            ..Default::default()
         }
     }
 
     pub fn broadcast() -> Self {
-        Header {
-            to: None,
-            is_meta: false,
-            is_cache: false,
+        Header::default()
+    }
+
+    /// Check if the message changes group membership instead of being sent to clients
+    pub fn is_group_control(&self) -> bool {
+        self.group_add.is_some() || self.group_remove.is_some()
+    }
+}
+
+/// Recipients of a routed message, either a single ID or a list of IDs
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Recipients {
+    One(ConnID),
+    Many(Vec<ConnID>),
+}
+
+impl Recipients {
+    pub fn contains(&self, id: ConnID) -> bool {
+        match self {
+            Self::One(to) => *to == id,
+            Self::Many(to) => to.contains(&id),
+        }
+    }
+}
+
+/// Named groups of connections in a room, assigned by the process
+#[derive(Debug, Default, Serialize)]
+pub struct Groups(BTreeMap<String, BTreeSet<ConnID>>);
+
+impl Groups {
+    pub fn add(&mut self, group: &str, conn: ConnID) {
+        self.0.entry(group.to_string()).or_default().insert(conn);
+    }
+
+    pub fn remove(&mut self, group: &str, conn: ConnID) {
+        if let Some(members) = self.0.get_mut(group) {
+            members.remove(&conn);
+            if members.is_empty() {
+                self.0.remove(group);
+            }
         }
     }
+
+    /// Remove the connection from all groups
+    pub fn remove_conn(&mut self, conn: ConnID) {
+        self.0.retain(|_, members| {
+            members.remove(&conn);
+            !members.is_empty()
+        });
+    }
+
+    pub fn members(&self, group: &str) -> Vec<ConnID> {
+        self.0
+            .get(group)
+            .map(|members| members.iter().copied().collect())
+            .unwrap_or_default()
+    }
 }
 
+pub type SharedGroups = Arc<RwLock<Groups>>;
+
 #[derive(Debug)]
 pub enum Event {
     Connect {
@@ -54,18 +152,75 @@ pub enum Event {
         room: RoomID,
         conn: ConnID,
     },
+    IdleTimeout {
+        room: RoomID,
+        timer: tokio::task::Id,
+    },
     ProcessExit {
         room: RoomID,
+        conn: Option<ConnID>,
         code: Option<i32>,
+        reason: ExitReason,
         port: Option<PortID>,
     },
+    ProcessRestart {
+        room: RoomID,
+        conn: Option<ConnID>,
+        code: Option<i32>,
+        reason: ExitReason,
+        attempt: u32,
+    },
+    ClientLag {
+        room: RoomID,
+        conn: ConnID,
+        skipped: u64,
+    },
     ProcessMeta {
         room: RoomID,
         value: serde_json::Value,
     },
+    PoolRefill,
     Shutdown,
 }
 
+/// Command sent by the process on the control channel
+#[derive(Debug, Deserialize)]
+#[serde(tag = "t")]
+pub enum ControlCommand {
+    /// Replace the room metadata
+    Meta { data: serde_json::Value },
+    /// Close the connection of the clients
+    Kick {
+        conn: Recipients,
+        code: Option<u16>,
+        reason: Option<String>,
+    },
+    /// Add the client to a group
+    GroupAdd { group: String, conn: ConnID },
+    /// Remove the client from a group
+    GroupRemove { group: String, conn: ConnID },
+    /// Write a line to the scalesocket log
+    Log { msg: String },
+}
+
+/// Event sent to the process on the control channel
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "t")]
+pub enum ControlEvent {
+    /// The process serves the room, with the given clients connected
+    Room { room: RoomID, conns: Vec<ConnID> },
+    /// A client connected
+    Join {
+        conn: ConnID,
+        env: BTreeMap<String, String>,
+    },
+    /// A client disconnected
+    Leave {
+        conn: ConnID,
+        env: BTreeMap<String, String>,
+    },
+}
+
 /// Incoming and outgoing framing for a channel
 #[derive(Debug, Clone, Copy)]
 pub enum Framing {
@@ -112,6 +267,111 @@ pub enum Frame {
     JSON,
     #[clap(name = "gwsocket")]
     GWSocket,
+    #[clap(name = "lenprefix")]
+    LenPrefix,
+    Length,
+    #[clap(name = "msgpack")]
+    MsgPack,
+    CBOR,
+    Envelope,
+    #[clap(name = "jsonrpc")]
+    JSONRPC,
+}
+
+#[derive(Debug, clap::ValueEnum, Clone, Copy)]
+pub enum StopSignal {
+    Term,
+    Int,
+    Hup,
+    Quit,
+    Usr1,
+    Usr2,
+    Kill,
+}
+
+impl StopSignal {
+    pub fn as_raw(&self) -> i32 {
+        match self {
+            Self::Term => libc::SIGTERM,
+            Self::Int => libc::SIGINT,
+            Self::Hup => libc::SIGHUP,
+            Self::Quit => libc::SIGQUIT,
+            Self::Usr1 => libc::SIGUSR1,
+            Self::Usr2 => libc::SIGUSR2,
+            Self::Kill => libc::SIGKILL,
+        }
+    }
+}
+
+/// Destination for lines captured from child stderr
+#[derive(Debug, clap::ValueEnum, Clone, Copy, PartialEq)]
+pub enum Stderr {
+    Log,
+    Clients,
+    File,
+}
+
+/// Restart policy for a child process
+#[derive(Debug, clap::ValueEnum, Clone, Copy)]
+pub enum Restart {
+    Never,
+    OnFailure,
+    Always,
+}
+
+/// Policy for clients that fall behind the process output
+#[derive(Debug, clap::ValueEnum, Clone, Copy, PartialEq, Default)]
+pub enum Lag {
+    /// Drop the oldest messages for the client
+    #[default]
+    Drop,
+    /// Disconnect the client
+    Disconnect,
+    /// Stop reading the process output until the client catches up
+    Block,
+}
+
+/// How a child process ended
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitReason {
+    /// Child exited on its own
+    Exited,
+    /// Child exited after receiving the stop signal
+    Stopped,
+    /// Child was killed after the stop timeout expired
+    Killed,
+    /// Child was terminated for exceeding a resource limit
+    Limit(Limit),
+}
+
+impl ExitReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Exited => "exited",
+            Self::Stopped => "stopped",
+            Self::Killed => "killed",
+            Self::Limit(Limit::Cpu) => "cpu_limit",
+            Self::Limit(Limit::Memory) => "memory_limit",
+        }
+    }
+}
+
+/// Resource limit that terminated a child process
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    /// CPU time rlimit
+    Cpu,
+    /// Memory cap of the cgroup
+    Memory,
+}
+
+impl std::fmt::Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cpu => write!(f, "cpu"),
+            Self::Memory => write!(f, "memory"),
+        }
+    }
 }
 
 #[derive(Debug, clap::ValueEnum, Clone, Copy)]
@@ -160,9 +420,9 @@ pub type BoxedHistoryBuf<T, const N: usize> = Box<HistoryBuf<T, N>>;
 #[derive(Debug, Clone)]
 #[allow(clippy::large_enum_variant)]
 pub enum CacheBuffer {
-    Single(HistoryBuf<Message, 1>),
-    Tiny(HistoryBuf<Message, 8>),
-    Small(BoxedHistoryBuf<Message, 64>),
+    Single(HistoryBuf<(Header, Message), 1>),
+    Tiny(HistoryBuf<(Header, Message), 8>),
+    Small(BoxedHistoryBuf<(Header, Message), 64>),
 }
 
 impl CacheBuffer {
@@ -176,7 +436,7 @@ impl CacheBuffer {
         }
     }
 
-    pub fn write(&mut self, msg: Message) {
+    pub fn write(&mut self, msg: (Header, Message)) {
         match self {
             Self::Single(h) => h.write(msg),
             Self::Tiny(h) => h.write(msg),
@@ -187,33 +447,31 @@ impl CacheBuffer {
     /// Returns a copy of the cache content in FIFO order
     pub fn to_vec(&self) -> Vec<(Header, Message)> {
         match self {
-            Self::Single(h) => h
-                .oldest_ordered()
-                .cloned()
-                .map(|msg| (Header::broadcast(), msg))
-                .collect(),
-            Self::Tiny(h) => h
-                .oldest_ordered()
-                .cloned()
-                .map(|msg| (Header::broadcast(), msg))
-                .collect(),
-            Self::Small(h) => h
-                .oldest_ordered()
-                .cloned()
-                .map(|msg| (Header::broadcast(), msg))
-                .collect(),
+            Self::Single(h) => h.oldest_ordered().cloned().collect(),
+            Self::Tiny(h) => h.oldest_ordered().cloned().collect(),
+            Self::Small(h) => h.oldest_ordered().cloned().collect(),
         }
     }
 }
 
 // Channel for app events
-pub type EventTx = mpsc::UnboundedSender<Event>;
-pub type EventRx = mpsc::UnboundedReceiver<Event>;
+// Bounded by --eventbuffer, the event loop itself never sends to it, so that it cannot block on itself
+pub type EventTx = mpsc::Sender<Event>;
+pub type EventRx = mpsc::Receiver<Event>;
 
 // Channel for passing data to child process
-pub type ToProcessTx = mpsc::UnboundedSender<Message>;
-pub type ToProcessRx = mpsc::UnboundedReceiver<Message>;
-pub type ToProcessRxStream = UnboundedReceiverStream<Message>;
+pub type ToProcessTx = mpsc::Sender<Message>;
+pub type ToProcessRx = mpsc::Receiver<Message>;
+pub type ToProcessRxStream = ReceiverStream<Message>;
+
+// Channel for passing join and leave messages to child process
+// Unbounded, since they must not be dropped when the input of the child is full
+pub type LifecycleTx = mpsc::UnboundedSender<Message>;
+pub type LifecycleRx = mpsc::UnboundedReceiver<Message>;
+
+// Channel for passing server events to the control channel of child process
+pub type ControlTx = mpsc::UnboundedSender<ControlEvent>;
+pub type ControlRx = mpsc::UnboundedReceiver<ControlEvent>;
 
 // Channel for triggering shutdown of child process
 pub type ShutdownTx = oneshot::Sender<()>;
@@ -225,5 +483,31 @@ pub type FromProcessTx = broadcast::Sender<(Header, Message)>;
 pub type FromProcessRx = broadcast::Receiver<(Header, Message)>;
 pub type FromProcessTxAny = Box<dyn tokio::io::AsyncWrite + Unpin + Send>;
 pub type FromProcessRxAny = Box<dyn futures::Stream<Item = IOResult<Bytes>> + Unpin + Send>;
+pub type FromProcessErrAny = Box<dyn futures::Stream<Item = IOResult<String>> + Unpin + Send>;
+pub type FromProcessFrameAny = Box<dyn futures::Stream<Item = IOResult<Message>> + Unpin + Send>;
+pub type ToProcessFrameAny = Box<dyn futures::Sink<Message, Error = IOError> + Unpin + Send>;
+
+/// Handles for communicating with a running process
+#[derive(Debug)]
+pub struct ProcessSenders {
+    pub cast_tx: FromProcessTx,
+    pub tx: ToProcessTx,
+    pub lifecycle_tx: LifecycleTx,
+    pub kill_tx: ShutdownTx,
+    pub control_tx: ControlTx,
+    /// Notified when a client has read process output, for `Lag::Block`
+    pub drained: Arc<Notify>,
+    /// Number of times the child has exited, locked while joins are sent to the child
+    pub exits: Arc<Mutex<u32>>,
+}
 
-pub type ProcessSenders = (FromProcessTx, ToProcessTx, ShutdownTx);
+// Channel for binding a pooled child process to a room, with the bind message
+pub type Binding = (
+    RoomID,
+    Option<String>,
+    Option<Arc<Mutex<CacheBuffer>>>,
+    SharedGroups,
+    Arc<Barrier>,
+);
+pub type BindTx = oneshot::Sender<Binding>;
+pub type BindRx = oneshot::Receiver<Binding>;
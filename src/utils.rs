@@ -1,11 +1,13 @@
 use {
     std::collections::HashMap,
     std::env,
+    std::io::{Error as IOError, Result as IOResult},
+    std::os::unix::process::ExitStatusExt,
     std::process::{ExitStatus, Stdio},
-    tokio::process::Command,
+    tokio::process::{Child, Command},
 };
 
-use crate::types::PortID;
+use crate::types::{PortID, StopSignal};
 
 pub fn run(
     program: &str,
@@ -13,6 +15,7 @@ pub fn run(
     port: Option<PortID>,
     env_extra: HashMap<String, String>,
     env_allowlist: &[String],
+    capture_stderr: bool,
 ) -> Command {
     // Combine filtered environment with external variables
     let env: HashMap<String, String> = env::vars()
@@ -31,6 +34,9 @@ pub fn run(
     if let Some(port) = port {
         cmd.env("PORT", port.to_string());
     }
+    if capture_stderr {
+        cmd.stderr(Stdio::piped());
+    }
     cmd
 }
 
@@ -38,6 +44,23 @@ pub fn exit_code<T>(status: Result<ExitStatus, T>) -> Option<i32> {
     status.ok().and_then(|s| s.code()).or(None)
 }
 
+pub fn exit_signal<T>(status: &Result<ExitStatus, T>) -> Option<i32> {
+    status.as_ref().ok().and_then(|s| s.signal())
+}
+
+/// Send a signal to a child process that has not been reaped yet
+pub fn send_signal(child: &Child, signal: StopSignal) -> IOResult<()> {
+    let pid = child
+        .id()
+        .ok_or_else(|| IOError::other("process already exited"))?;
+
+    // SAFETY: kill only reads its arguments, and the pid is held by the unreaped child
+    match unsafe { libc::kill(pid as libc::pid_t, signal.as_raw()) } {
+        0 => Ok(()),
+        _ => Err(IOError::last_os_error()),
+    }
+}
+
 /// Utility filters for Warp
 pub mod warpext {
     use std::{collections::HashMap, convert::Infallible};
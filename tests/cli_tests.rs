@@ -1,5 +1,3 @@
-use trycmd;
-
 #[test]
 fn cli_tests() {
     trycmd::TestCases::new().case("assets/cli.md");